@@ -1,12 +1,29 @@
-use crate::jsonmodels::{MapNodes, Node};
-use std::collections::hash_map::Entry;
 pub use crate::engine::engine_options::EngineOptions;
-use std::process::exit;
+use crate::jsonmodels::{MapNodes, Node, TypedValue};
+use indexmap::map::Entry;
 
+pub mod command;
 pub(crate) mod engine_options;
 pub mod errors;
+pub mod glob;
+pub mod key_path;
+pub mod meta;
+pub mod time;
+mod trail;
+pub mod units;
+pub mod value_parser;
 
-use crate::engine::engine_options::HowToDictInArray;
+use crate::engine::command::{node_at_path, remove_at_path, Command};
+use crate::engine::engine_options::{
+    HowToArrayInArray, HowToDictInArray, HowToLeafInDict, HowToRepeatedLeaf, KeyOrder, MetaMode,
+    PrefixLocation, RecordBoundary,
+};
+use crate::engine::key_path::{
+    check_quotes, find_unquoted, is_plain_path, parse_key_path, split_type_annotation, KeySegment,
+};
+use crate::engine::meta::Meta;
+use crate::engine::trail::{Location, Trail};
+use crate::engine::value_parser::{heredoc_terminator, parse_block_value, parse_value};
 pub use errors::Error;
 pub use errors::Result;
 
@@ -15,115 +32,684 @@ pub fn string_to_dict(mut dotted_keys: Vec<String>, val: Node) -> MapNodes {
     let k = dotted_keys.pop().unwrap();
 
     h.nodes.insert(k, val);
-    if dotted_keys.len() == 0 {
+    if dotted_keys.is_empty() {
         return h;
     }
     string_to_dict(dotted_keys, Node::Dict(h))
 }
-pub fn extend_hashmap(h: &mut MapNodes, mut h2: MapNodes, options: &EngineOptions) -> Result<()> {
-    for (k, value_to_insert) in h2.nodes.drain() {
-        match h.nodes.entry(k.clone()) {
+pub fn extend_hashmap(
+    h: &mut MapNodes,
+    h2: MapNodes,
+    options: &EngineOptions,
+    trail: &mut Trail,
+) -> Result<()> {
+    extend_hashmap_at(h, h2, options, &mut Vec::new(), trail)
+}
+
+/// Same as [extend_hashmap], `path` being the dotted key of `h` in the whole tree
+fn extend_hashmap_at(
+    h: &mut MapNodes,
+    mut h2: MapNodes,
+    options: &EngineOptions,
+    path: &mut Vec<String>,
+    trail: &mut Trail,
+) -> Result<()> {
+    for (k, value_to_insert) in h2.nodes.drain(..) {
+        path.push(k.clone());
+        let result = match h.nodes.entry(k) {
             Entry::Occupied(mut existing_entry) => {
                 if options.verbosity > 0 {
                     eprintln!("key {} already exists", existing_entry.key());
                     eprintln!("value = {:?}", existing_entry.get());
                     eprintln!("value to insert = {:?}", value_to_insert);
                 }
-                match existing_entry.get_mut() {
-                    Node::Leaf(s) => {
-                        match value_to_insert {
-                            Node::Leaf(s_to_insert) => {
-                                // convert existing value to array
-                                let mut a = Vec::new();
-                                a.push(Node::Leaf(s.clone()));
-                                a.push(Node::Leaf(s_to_insert));
-                                *existing_entry.get_mut() = Node::Array(a);
-                            }
-                            Node::Dict(d_to_insert) => {
-                                let mut h = MapNodes::new();
-                                h.nodes.insert("value".to_string(), Node::Leaf(s.clone()));
-                                h.nodes.extend(d_to_insert.nodes);
-                                *existing_entry.get_mut() = Node::Dict(h);
-                            }
-                            Node::Array(_) => {
-                                todo!("value is an array")
-                            }
-                        }
-                    }
-                    Node::Dict(d) => match value_to_insert {
-                        Node::Leaf(_) => {
-                            todo!("value is a string")
-                        }
-                        Node::Dict(d2) => {
-                            extend_hashmap(d, d2, &options)?;
-                        }
-                        Node::Array(_) => {
-                            todo!("value is an array")
-                        }
-                    },
-                    Node::Array(existing_array) => match value_to_insert {
-                        Node::Leaf(s_to_insert) => {
-                            existing_array.push(Node::Leaf(s_to_insert));
-                        }
-                        Node::Dict(d) => match options.how_to_dict_in_array {
-                            HowToDictInArray::GenerateError => return Err(Error::HowToDictInArray),
-                            HowToDictInArray::MergeDictInArray => {
-                                existing_array.push(Node::Dict(d));
-                            }
-                            HowToDictInArray::MakeArrayAsDictValue => {
-                                unimplemented!()
-                            }
-                        },
-                        Node::Array(_) => {
-                            todo!("value to insert is an array")
-                        }
-                    },
-                }
+                trail.enter(Location::Key(existing_entry.key().clone()));
+                let result = merge_node(
+                    existing_entry.get_mut(),
+                    value_to_insert,
+                    options,
+                    path,
+                    trail,
+                );
+                trail.leave();
+                result
             }
             Entry::Vacant(e) => {
-                e.insert(value_to_insert);
+                trail.key_added(e.key());
+                e.insert(new_node(value_to_insert, options, path));
+                Ok(())
+            }
+        };
+        path.pop();
+        result?;
+    }
+    Ok(())
+}
+
+/// Prepare a node inserted under a key not seen yet
+fn new_node(node: Node, options: &EngineOptions, path: &mut Vec<String>) -> Node {
+    match node {
+        Node::Leaf(s) => match options.how_to_repeated_leaf_for(path) {
+            HowToRepeatedLeaf::AlwaysArray => Node::Array(vec![Node::Leaf(s)]),
+            _ => Node::Leaf(s),
+        },
+        Node::Dict(mut d) => {
+            let mut h = MapNodes::new();
+            for (k, v) in d.nodes.drain(..) {
+                path.push(k.clone());
+                let v = new_node(v, options, path);
+                path.pop();
+                h.nodes.insert(k, v);
             }
+            Node::Dict(h)
         }
+        Node::Array(a) => Node::Array(a),
+    }
+}
+
+/// Key used to keep a leaf when it meets a dict, see [HowToLeafInDict]
+const LEAF_IN_DICT_KEY: &str = "value";
+/// Key used to keep an array when it meets a dict, see [HowToDictInArray]
+const ARRAY_IN_DICT_KEY: &str = "array";
+
+fn single_key_map(k: &str, node: Node) -> MapNodes {
+    let mut h = MapNodes::new();
+    h.nodes.insert(k.to_string(), node);
+    h
+}
+
+/// Merge `value_to_insert` into `existing`, both being found under the dotted key `path`.
+///
+/// The changes are recorded in `trail`, `existing` being left half merged when
+/// an error is returned.
+pub fn merge_node(
+    existing: &mut Node,
+    value_to_insert: Node,
+    options: &EngineOptions,
+    path: &mut Vec<String>,
+    trail: &mut Trail,
+) -> Result<()> {
+    match existing {
+        Node::Leaf(s) => match value_to_insert {
+            Node::Leaf(s_to_insert) => match options.how_to_repeated_leaf_for(path) {
+                HowToRepeatedLeaf::AppendArray | HowToRepeatedLeaf::AlwaysArray => {
+                    // convert existing value to array
+                    *existing = Node::Array(vec![Node::Leaf(s.clone()), Node::Leaf(s_to_insert)]);
+                    trail.wrapped(Location::Index(0));
+                }
+                HowToRepeatedLeaf::LastWins => {
+                    trail.replaced(std::mem::replace(existing, Node::Leaf(s_to_insert)));
+                }
+                HowToRepeatedLeaf::FirstWins => {}
+                HowToRepeatedLeaf::GenerateError => {
+                    return Err(Error::RepeatedLeaf(path.join(".")))
+                }
+            },
+            Node::Dict(d_to_insert) => match options.how_to_leaf_in_dict {
+                HowToLeafInDict::GenerateError => return Err(Error::HowToLeafInDict),
+                HowToLeafInDict::MakeLeafAsDictValue => {
+                    let h = single_key_map(LEAF_IN_DICT_KEY, Node::Leaf(s.clone()));
+                    *existing = Node::Dict(h);
+                    trail.wrapped(Location::Key(LEAF_IN_DICT_KEY.to_string()));
+                    let Node::Dict(h) = existing else {
+                        unreachable!()
+                    };
+                    extend_hashmap_at(h, d_to_insert, options, path, trail)?;
+                }
+            },
+            Node::Array(a_to_insert) => match options.how_to_array_in_array {
+                HowToArrayInArray::GenerateError => return Err(Error::HowToArrayInArray),
+                HowToArrayInArray::Concatenate => {
+                    let mut a = vec![Node::Leaf(s.clone())];
+                    a.extend(a_to_insert);
+                    *existing = Node::Array(a);
+                    trail.wrapped(Location::Index(0));
+                }
+                HowToArrayInArray::Nest => {
+                    *existing = Node::Array(vec![Node::Leaf(s.clone()), Node::Array(a_to_insert)]);
+                    trail.wrapped(Location::Index(0));
+                }
+            },
+        },
+        Node::Dict(d) => match value_to_insert {
+            Node::Leaf(s_to_insert) => match options.how_to_leaf_in_dict {
+                HowToLeafInDict::GenerateError => return Err(Error::HowToLeafInDict),
+                HowToLeafInDict::MakeLeafAsDictValue => {
+                    let h = single_key_map(LEAF_IN_DICT_KEY, Node::Leaf(s_to_insert));
+                    extend_hashmap_at(d, h, options, path, trail)?;
+                }
+            },
+            Node::Dict(d2) => {
+                extend_hashmap_at(d, d2, options, path, trail)?;
+            }
+            Node::Array(a_to_insert) => match options.how_to_dict_in_array {
+                HowToDictInArray::GenerateError => return Err(Error::HowToDictInArray),
+                HowToDictInArray::MergeDictInArray => {
+                    let mut a = vec![Node::Dict(std::mem::take(d))];
+                    a.extend(a_to_insert);
+                    *existing = Node::Array(a);
+                    trail.wrapped(Location::Index(0));
+                }
+                HowToDictInArray::MakeArrayAsDictValue => {
+                    let h = single_key_map(ARRAY_IN_DICT_KEY, Node::Array(a_to_insert));
+                    extend_hashmap_at(d, h, options, path, trail)?;
+                }
+            },
+        },
+        Node::Array(existing_array) => match value_to_insert {
+            Node::Leaf(s_to_insert) => match options.how_to_repeated_leaf_for(path) {
+                HowToRepeatedLeaf::AppendArray | HowToRepeatedLeaf::AlwaysArray => {
+                    trail.appended(existing_array.len());
+                    existing_array.push(Node::Leaf(s_to_insert));
+                }
+                HowToRepeatedLeaf::LastWins => {
+                    trail.replaced(std::mem::replace(existing, Node::Leaf(s_to_insert)));
+                }
+                HowToRepeatedLeaf::FirstWins => {}
+                HowToRepeatedLeaf::GenerateError => {
+                    return Err(Error::RepeatedLeaf(path.join(".")))
+                }
+            },
+            Node::Dict(d) => match options.how_to_dict_in_array {
+                HowToDictInArray::GenerateError => return Err(Error::HowToDictInArray),
+                HowToDictInArray::MergeDictInArray => {
+                    trail.appended(existing_array.len());
+                    existing_array.push(Node::Dict(d));
+                }
+                HowToDictInArray::MakeArrayAsDictValue => {
+                    let a = std::mem::take(existing_array);
+                    *existing = Node::Dict(single_key_map(ARRAY_IN_DICT_KEY, Node::Array(a)));
+                    trail.wrapped(Location::Key(ARRAY_IN_DICT_KEY.to_string()));
+                    let Node::Dict(h) = existing else {
+                        unreachable!()
+                    };
+                    extend_hashmap_at(h, d, options, path, trail)?;
+                }
+            },
+            Node::Array(a_to_insert) => match options.how_to_array_in_array {
+                HowToArrayInArray::GenerateError => return Err(Error::HowToArrayInArray),
+                HowToArrayInArray::Concatenate => {
+                    trail.appended(existing_array.len());
+                    existing_array.extend(a_to_insert);
+                }
+                HowToArrayInArray::Nest => {
+                    trail.appended(existing_array.len());
+                    existing_array.push(Node::Array(a_to_insert));
+                }
+            },
+        },
     }
     Ok(())
 }
 
+/// Insert `value` in `h` at the key made of `segments`, the array markers being
+/// resolved against the values already in `h`.
+pub fn insert_at_path(
+    h: &mut MapNodes,
+    segments: &[KeySegment],
+    value: Node,
+    options: &EngineOptions,
+    trail: &mut Trail,
+) -> Result<()> {
+    insert_in_map(h, segments, value, options, &mut Vec::new(), trail)
+}
+
+fn insert_in_map(
+    h: &mut MapNodes,
+    segments: &[KeySegment],
+    value: Node,
+    options: &EngineOptions,
+    path: &mut Vec<String>,
+    trail: &mut Trail,
+) -> Result<()> {
+    let (k, rest) = match segments.split_first() {
+        Some((KeySegment::Key(k), rest)) => (k, rest),
+        _ => return Err(Error::NotAnArray(path.join("."))),
+    };
+    path.push(k.clone());
+    let result = match h.nodes.get_mut(k) {
+        Some(existing) => {
+            trail.enter(Location::Key(k.clone()));
+            let result = insert_in_node(existing, rest, value, options, path, trail);
+            trail.leave();
+            result
+        }
+        None => build_node(rest, value, path).map(|node| {
+            let node = new_node(node, options, path);
+            trail.key_added(k);
+            h.nodes.insert(k.clone(), node);
+        }),
+    };
+    path.pop();
+    result
+}
+
+fn insert_in_node(
+    node: &mut Node,
+    segments: &[KeySegment],
+    value: Node,
+    options: &EngineOptions,
+    path: &mut Vec<String>,
+    trail: &mut Trail,
+) -> Result<()> {
+    match (segments.first(), node) {
+        (None, node) => merge_node(node, value, options, path, trail),
+        (Some(KeySegment::Key(_)), Node::Dict(d)) => {
+            insert_in_map(d, segments, value, options, path, trail)
+        }
+        (Some(KeySegment::Key(_)), node) => {
+            let value = build_node(segments, value, path)?;
+            merge_node(node, value, options, path, trail)
+        }
+        (Some(KeySegment::Index(i)), Node::Array(a)) => {
+            let len = a.len() as i64;
+            let i = if *i < 0 { len + i } else { *i };
+            if i < 0 || i >= len {
+                return Err(Error::IndexOutOfRange(path.join(".")));
+            }
+            trail.enter(Location::Index(i as usize));
+            if let ([_], Node::Leaf(_)) = (segments, &a[i as usize]) {
+                // a leaf addressed by its index is set, not repeated
+                trail.replaced(std::mem::replace(&mut a[i as usize], value));
+                trail.leave();
+                return Ok(());
+            }
+            let result = insert_in_node(
+                &mut a[i as usize],
+                &segments[1..],
+                value,
+                options,
+                path,
+                trail,
+            );
+            trail.leave();
+            result
+        }
+        (Some(KeySegment::Append), Node::Array(a)) => {
+            let value = build_node(&segments[1..], value, path)?;
+            trail.appended(a.len());
+            a.push(new_node(value, options, path));
+            Ok(())
+        }
+        (Some(_), _) => Err(Error::NotAnArray(path.join("."))),
+    }
+}
+
+/// Build the node holding `value` at `segments`, when nothing exists there yet
+fn build_node(segments: &[KeySegment], value: Node, path: &[String]) -> Result<Node> {
+    let mut node = value;
+    for segment in segments.iter().rev() {
+        node = match segment {
+            KeySegment::Key(k) => Node::Dict(single_key_map(k, node)),
+            KeySegment::Append => Node::Array(vec![node]),
+            KeySegment::Index(_) => return Err(Error::IndexOutOfRange(path.join("."))),
+        };
+    }
+    Ok(node)
+}
+
+/// What the engine asks its caller to do, ie write a json document or stop
+/// reading
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Output {
+    /// A json document to write, on its own line
+    Json(String),
+    /// Stop reading lines, the values being written already
+    Stop,
+}
+
+/// A multi-line value being read, opened by `;key:<<TERMINATOR`
+struct OpenBlock {
+    dotted_key: String,
+    value_type: Option<String>,
+    terminator: String,
+    lines: Vec<String>,
+    /// Line number of the line opening the block
+    line_number: i64,
+}
+
+/// Undo logs of the values and of their meta data, see [Engine::atomically]
+#[derive(Default)]
+struct Trails {
+    values: Trail,
+    meta: Trail,
+}
+
+/// A key prefix opened by `;!begin`
+#[derive(Clone)]
+struct Scope {
+    /// The key as written, matched by `;!end`
+    dotted_key: String,
+    /// The key with its `[]` replaced by the index of the element appended
+    /// when the scope was opened, ie `it[2]` for `it[]`
+    resolved: String,
+}
+
 pub struct Engine {
     options: EngineOptions,
-    pub values: MapNodes,
+    values: MapNodes,
+    open_block: Option<OpenBlock>,
+    /// Number of lines added so far
+    line_number: i64,
+    meta: Meta,
+    /// Values of the last snapshot, kept to write only the changes
+    last_snapshot: Option<MapNodes>,
+    /// Keys opened by `;!begin`, prepended to the keys of the lines
+    scopes: Vec<Scope>,
+    /// The group keys of the options, parsed once
+    group_keys: Result<Vec<Vec<KeySegment>>>,
+    /// Keys holding an array of objects, set by `;!next`
+    groups: Vec<Vec<KeySegment>>,
+    /// Outputs not taken yet by the caller
+    outputs: Vec<Output>,
 }
 
 impl Engine {
     pub fn new(options: EngineOptions) -> Self {
+        let group_keys = options
+            .group_keys
+            .iter()
+            .map(|group| parse_key_path(group, &options.path_separator))
+            .collect();
         Engine {
             values: MapNodes::new(),
-            options: options,
+            options,
+            open_block: None,
+            line_number: 0,
+            meta: Meta::default(),
+            last_snapshot: None,
+            scopes: Vec::new(),
+            group_keys,
+            groups: Vec::new(),
+            outputs: Vec::new(),
         }
     }
 
-    pub fn handle_special_lines(&mut self, command: &str) {
+    fn clear(&mut self) {
+        self.values.nodes.clear();
+        self.meta.clear();
+        self.last_snapshot = None;
+    }
+
+    /// Execute a control command, ie `;!delete:a.b`
+    pub fn execute(&mut self, command: Command) -> Result<()> {
         if self.options.verbosity > 0 {
-            eprintln!("handle_special_lines: command = {}", command);
+            eprintln!("execute: command = {:?}", command);
+        }
+        if matches!(
+            command,
+            Command::Begin(_) | Command::Set { .. } | Command::Push { .. } | Command::Next(_)
+        ) {
+            // the values go to a new element of the scopes whose element is gone
+            self.atomically(|engine, trails| engine.refresh_scopes(trails))?;
         }
         match command {
-            "clear" => {
-                self.values.nodes.clear();
+            Command::Clear => self.clear(),
+            Command::Flush => self.flush(),
+            Command::Snapshot => self.snapshot(),
+            Command::Exit => {
+                self.flush();
+                self.outputs.push(Output::Stop);
             }
-
-            "end" => {
-                println!("{}", self.get_json());
-                self.values.nodes.clear();
-                exit(0);
+            Command::Begin(dotted_key) => {
+                let resolved =
+                    self.atomically(|engine, trails| engine.resolve_appends(&dotted_key, trails))?;
+                self.scopes.push(Scope {
+                    dotted_key,
+                    resolved,
+                });
+            }
+            Command::End(dotted_key) => match (self.scopes.last(), dotted_key) {
+                (None, _) => return Err(Error::UnbalancedScope("end without begin".to_string())),
+                (Some(scope), Some(dotted_key)) if scope.dotted_key != dotted_key => {
+                    return Err(Error::UnbalancedScope(format!(
+                        "end of {} in the scope of {}",
+                        dotted_key, scope.dotted_key
+                    )))
+                }
+                _ => {
+                    self.scopes.pop();
+                }
+            },
+            Command::Delete(dotted_key) => {
+                self.remove(&self.scoped(&dotted_key))?;
             }
+            Command::Set { dotted_key, value } => {
+                let (dotted_key, value_type) = split_type_annotation(&dotted_key);
+                let value = parse_value(&value, value_type, &self.options)?;
+                self.set(&self.scoped(dotted_key), value)?;
+            }
+            Command::Push { dotted_key, value } => {
+                let (dotted_key, value_type) = split_type_annotation(&dotted_key);
+                let value = parse_value(&value, value_type, &self.options)?;
+                let dotted_key = format!("{}[]", self.scoped(dotted_key));
+                self.insert_values(vec![(dotted_key, value)], self.line_number)?;
+            }
+            Command::Pop(dotted_key) => {
+                let dotted_key = self.scoped(&dotted_key);
+                let segments = parse_key_path(&dotted_key, &self.options.path_separator)?;
+                let popped = match node_at_path(&mut self.values, &segments) {
+                    Some(Node::Array(a)) => a.pop(),
+                    Some(_) => return Err(Error::NotAnArray(dotted_key)),
+                    None => return Err(Error::MissingKey(dotted_key)),
+                };
+                if popped.is_none() {
+                    return Err(Error::IndexOutOfRange(dotted_key));
+                }
+                if let Some(Node::Array(a)) = node_at_path(&mut self.meta.lines, &segments) {
+                    a.pop();
+                }
+            }
+            Command::Rename { from, to } => {
+                let (from, to) = (self.scoped(&from), self.scoped(&to));
+                let backup = (self.values.clone(), self.meta.clone());
+                let (value, meta) = self.remove(&from)?;
+                let mut trail = Trail::default();
+                let result = insert_dotted(&mut self.values, &to, value, &self.options, &mut trail)
+                    .and_then(|_| match meta {
+                        Some(meta) => insert_dotted(
+                            &mut self.meta.lines,
+                            &to,
+                            meta,
+                            &self.options,
+                            &mut trail,
+                        ),
+                        None => Ok(()),
+                    });
+                if result.is_err() {
+                    (self.values, self.meta) = backup;
+                }
+                result?;
+            }
+            Command::Next(dotted_key) => {
+                let dotted_key = self.scoped(&dotted_key);
+                let segments = parse_key_path(&dotted_key, &self.options.path_separator)?;
+                match node_at_path(&mut self.values, &segments) {
+                    None | Some(Node::Array(_)) => self.atomically(|engine, trails| {
+                        engine.push_group_element(&segments, trails)
+                    })?,
+                    Some(_) => return Err(Error::NotAnArray(dotted_key)),
+                }
+                if !self.groups.contains(&segments) {
+                    self.groups.push(segments);
+                }
+            }
+            Command::Transpose(dotted_key) => {
+                let dotted_key = self.scoped(&dotted_key);
+                let segments = parse_key_path(&dotted_key, &self.options.path_separator)?;
+                let node = node_at_path(&mut self.values, &segments)
+                    .ok_or_else(|| Error::MissingKey(dotted_key.clone()))?;
+                if !node.transpose() {
+                    return Err(Error::CannotTranspose(dotted_key));
+                }
+                if let Some(node) = node_at_path(&mut self.meta.lines, &segments) {
+                    node.transpose();
+                }
+            }
+            Command::Emit(dotted_key) => {
+                let dotted_key = self.scoped(&dotted_key);
+                let segments = parse_key_path(&dotted_key, &self.options.path_separator)?;
+                let (value, meta) = self.remove(&dotted_key)?;
+                let mut node = self.meta.render_node(
+                    value,
+                    meta.as_ref(),
+                    self.options.meta_mode,
+                    self.options.time_format,
+                );
+                self.arrange(&segments, &mut node);
+                let json = serde_json::to_string(&node).unwrap();
+                self.outputs.push(Output::Json(json));
+            }
+        }
+        Ok(())
+    }
+
+    /// The dotted key in the current scope, ie `a.b.c` for `c` after `;!begin:a.b`
+    fn scoped(&self, dotted_key: &str) -> String {
+        let mut scoped = self
+            .scopes
+            .iter()
+            .map(|scope| scope.resolved.as_str())
+            .collect::<Vec<_>>()
+            .join(&self.options.path_separator);
+        if !scoped.is_empty() && !dotted_key.is_empty() {
+            scoped += &self.options.path_separator;
+        }
+        scoped + dotted_key
+    }
+
+    /// Remove the value at a dotted key, returning it with its meta data
+    fn remove(&mut self, dotted_key: &str) -> Result<(Node, Option<Node>)> {
+        let segments = parse_key_path(dotted_key, &self.options.path_separator)?;
+        let value = remove_at_path(&mut self.values, &segments)
+            .ok_or_else(|| Error::MissingKey(dotted_key.to_string()))?;
+        let meta = remove_at_path(&mut self.meta.lines, &segments);
+        Ok((value, meta))
+    }
+
+    /// Set the value at a dotted key, replacing the one already there
+    fn set(&mut self, dotted_key: &str, value: Node) -> Result<()> {
+        let segments = parse_key_path(dotted_key, &self.options.path_separator)?;
+        let meta = match self.options.meta_mode {
+            MetaMode::Off => None,
+            _ => Some(self.meta.mirror(&value, self.line_number)),
+        };
+        let Some(existing) = node_at_path(&mut self.values, &segments) else {
+            return self.insert_values(vec![(dotted_key.to_string(), value)], self.line_number);
+        };
+        *existing = value;
+        if let (Some(meta), Some(existing)) = (meta, node_at_path(&mut self.meta.lines, &segments))
+        {
+            *existing = meta;
+        }
+        Ok(())
+    }
+
+    /// Write the json and start over
+    pub fn flush(&mut self) {
+        let json = self.get_json();
+        self.outputs.push(Output::Json(json));
+        self.clear();
+    }
+
+    /// Write the json without starting over, or only the changes since the
+    /// previous snapshot if enabled
+    pub fn snapshot(&mut self) {
+        let json = self.get_snapshot_json();
+        self.outputs.push(Output::Json(json));
+    }
+
+    /// The outputs produced since the previous call, in order. They are kept
+    /// even when adding a line fails, ie a record written before the error
+    pub fn take_outputs(&mut self) -> Vec<Output> {
+        std::mem::take(&mut self.outputs)
+    }
 
-            "flush" => {
-                println!("{}", self.get_json());
-                self.values.nodes.clear();
-                // exit(0);
+    fn get_snapshot_json(&mut self) -> String {
+        let values = self.output_values();
+        if !self.options.snapshot_changes_only {
+            return serde_json::to_string(&values).unwrap();
+        }
+        let json = match &self.last_snapshot {
+            Some(last) => serde_json::to_string(&values.changed_since(last)).unwrap(),
+            None => serde_json::to_string(&values).unwrap(),
+        };
+        self.last_snapshot = Some(values);
+        json
+    }
+
+    /// Write the current record, if any
+    pub fn end_record(&mut self) {
+        if !self.values.nodes.is_empty() {
+            self.flush();
+        }
+    }
+
+    pub fn is_record_mode(&self) -> bool {
+        !self.options.record_boundaries.is_empty()
+    }
+
+    /// Whether a line without prefix ends the record
+    fn is_boundary_line(&self, line: &str) -> bool {
+        self.options.record_boundaries.iter().any(|b| match b {
+            RecordBoundary::BlankLine => line.trim().is_empty(),
+            RecordBoundary::UnmatchedLine => true,
+            _ => false,
+        })
+    }
+
+    fn is_marker_key(&self, dotted_key: &str) -> bool {
+        self.options
+            .record_boundaries
+            .iter()
+            .any(|b| matches!(b, RecordBoundary::MarkerKey(k) if k == dotted_key))
+    }
+
+    /// Whether setting this key ends the record, being already set
+    fn is_repeated_boundary_key(&self, dotted_key: &str) -> bool {
+        self.options
+            .record_boundaries
+            .iter()
+            .any(|b| matches!(b, RecordBoundary::RepeatedKey(k) if k == dotted_key))
+            && self.has_key(dotted_key)
+    }
+
+    /// Whether the values hold a dotted key, array indexes not being looked up
+    fn has_key(&self, dotted_key: &str) -> bool {
+        let Ok(segments) = parse_key_path(dotted_key, &self.options.path_separator) else {
+            return false;
+        };
+        let mut h = &self.values;
+        for (i, segment) in segments.iter().enumerate() {
+            let KeySegment::Key(k) = segment else {
+                return false;
+            };
+            match h.nodes.get(k) {
+                None => return false,
+                Some(_) if i + 1 == segments.len() => return true,
+                Some(Node::Dict(child)) => h = child,
+                Some(_) => return false,
             }
+        }
+        false
+    }
 
-            _ => {
-                eprintln!("unknown command: {}", command);
+    /// Split `line` around the prefix, returning the text to store as leading
+    /// text (if any) and what follows the prefix
+    fn find_prefix<'a>(&self, line: &'a str) -> Option<(Option<&'a str>, &'a str)> {
+        let prefix = self.options.line_prefix.as_str();
+        match &self.options.prefix_location {
+            PrefixLocation::Start => line.strip_prefix(prefix).map(|rest| (None, rest)),
+            PrefixLocation::Anywhere => line
+                .find(prefix)
+                .map(|i| (Some(&line[..i]), &line[i + prefix.len()..])),
+            PrefixLocation::AfterRegex(regex) => {
+                let captures = regex.captures(line)?;
+                let m = captures.get(0).unwrap();
+                let rest = line[m.end()..].strip_prefix(prefix)?;
+                let leading = captures.get(1).map_or(m.as_str(), |g| g.as_str());
+                Some((Some(leading), rest))
             }
         }
     }
@@ -132,49 +718,454 @@ impl Engine {
         if self.options.verbosity > 0 {
             eprintln!("add line to engine: line = {}", line);
         }
+        self.line_number += 1;
 
-        if line.starts_with(";") {
-            let line = &line[1..];
-            let parts = line.split_once(&self.options.key_to_value_delimiter);
+        if self.open_block.is_some() {
+            return self.add_block_line(line);
+        }
 
-            if let Some((dotted_key, value)) = parts {
-                let value = value.trim();
+        if let Some((leading_text, line)) = self.find_prefix(line) {
+            if let Some(command) = line.strip_prefix('!') {
+                let command = match self.split_key_value(command) {
+                    Some((name, argument)) => Command::parse(name, Some(argument))?,
+                    None => Command::parse(command, None)?,
+                };
+                return self.execute(command);
+            }
+            if let Some((dotted_key, value)) = self.split_key_value(line) {
+                // legacy form of the commands, ie `;stdout.loop:flush`
                 if dotted_key.starts_with("stdout.loop") {
-                    self.handle_special_lines(value);
-                    return Ok(());
+                    return self.execute(Command::parse_legacy(value)?);
+                }
+            }
+
+            let raw_pairs = self.split_pairs(line)?;
+            self.atomically(|engine, trails| engine.refresh_scopes(trails))?;
+            let last = raw_pairs.len().saturating_sub(1);
+            let mut pairs = Vec::new();
+            let mut block = None;
+            for (i, (dotted_key, value)) in raw_pairs.into_iter().enumerate() {
+                let (dotted_key, value_type) = split_type_annotation(dotted_key);
+                let dotted_key = self.scoped(dotted_key);
+                // only the last pair of a line can open a block
+                let terminator = heredoc_terminator(value)
+                    .filter(|_| i == last && self.options.multi_line_values);
+                if let Some(terminator) = terminator {
+                    block = Some(OpenBlock {
+                        dotted_key,
+                        value_type: value_type.map(str::to_string),
+                        terminator: terminator.to_string(),
+                        lines: Vec::new(),
+                        line_number: self.line_number,
+                    });
+                    continue;
+                }
+                let value = parse_value(value, value_type, &self.options)?;
+                pairs.push((dotted_key, value));
+            }
+
+            let pairs_count = pairs.len();
+            pairs.retain(|(dotted_key, _)| !self.is_marker_key(dotted_key));
+            let ends_record = pairs.len() != pairs_count;
+            if pairs
+                .iter()
+                .any(|(dotted_key, _)| self.is_repeated_boundary_key(dotted_key))
+            {
+                self.end_record();
+            }
+            if pairs.is_empty() && block.is_none() {
+                if ends_record {
+                    self.end_record();
+                }
+                return Ok(());
+            }
+
+            let leading_text = leading_text.map(str::trim).unwrap_or_default();
+            if let Some(key) = &self.options.leading_text_key {
+                if !leading_text.is_empty() {
+                    let value = Node::Leaf(TypedValue::String(leading_text.to_string()));
+                    pairs.push((key.clone(), value));
                 }
+            }
+
+            self.insert_values(pairs, self.line_number)?;
+            self.open_block = block;
+            if ends_record {
+                self.end_record();
+            }
+        } else if self.is_boundary_line(line) {
+            self.end_record();
+        }
+        Ok(())
+    }
+
+    /// Accumulate a line of the open block, inserting its value when the
+    /// terminator is found
+    fn add_block_line(&mut self, line: &str) -> Result<()> {
+        let line = line.trim_end_matches(['\n', '\r']);
+        let block = self.open_block.as_mut().unwrap();
+        if line.trim() != block.terminator {
+            block.lines.push(line.to_string());
+            return Ok(());
+        }
+
+        let block = self.open_block.take().unwrap();
+        let text = block.lines.join("\n");
+        let value = parse_block_value(&text, block.value_type.as_deref(), &self.options)?;
+        self.insert_values(vec![(block.dotted_key, value)], block.line_number)
+    }
+
+    /// Insert the values of a line, which is applied entirely or not at all
+    fn insert_values(&mut self, pairs: Vec<(String, Node)>, line_number: i64) -> Result<()> {
+        self.atomically(|engine, trails| {
+            for (dotted_key, value) in pairs {
+                engine.insert_value(&dotted_key, value, line_number, trails)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Run `f`, undoing its changes to the values and to their meta data when
+    /// it fails
+    fn atomically<T>(&mut self, f: impl FnOnce(&mut Self, &mut Trails) -> Result<T>) -> Result<T> {
+        let mut trails = Trails::default();
+        let result = f(self, &mut trails);
+        if result.is_err() {
+            trails.values.rollback(&mut self.values);
+            trails.meta.rollback(&mut self.meta.lines);
+        }
+        result
+    }
+
+    /// To be called once all the lines are added
+    pub fn finish(&mut self) -> Result<()> {
+        let scopes = std::mem::take(&mut self.scopes);
+        if let Some(block) = self.open_block.take() {
+            return Err(Error::UnterminatedBlock(block.dotted_key));
+        }
+        match scopes.last() {
+            Some(scope) => Err(Error::UnbalancedScope(format!(
+                "no end for {}",
+                scope.dotted_key
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Split what follows the prefix into (key, value) pairs
+    fn split_pairs<'a>(&self, line: &'a str) -> Result<Vec<(&'a str, &'a str)>> {
+        let separator = match &self.options.pair_separator {
+            Some(separator) if !separator.is_empty() => separator.as_str(),
+            _ => return Ok(self.split_pair(line)?.into_iter().collect()),
+        };
+
+        let mut pairs = Vec::new();
+        let mut rest = line;
+        loop {
+            let (pair, next) = match find_unquoted(rest, separator) {
+                Some(i) => (&rest[..i], Some(&rest[i + separator.len()..])),
+                None => (rest, None),
+            };
+            let pair = pair.trim();
+            if !pair.is_empty() {
+                let pair = self
+                    .split_pair(pair)?
+                    .ok_or_else(|| Error::MissingDelimiter(pair.to_string()))?;
+                pairs.push(pair);
+            }
+            match next {
+                Some(next) => rest = next,
+                None => return Ok(pairs),
+            }
+        }
+    }
+
+    /// Split a pair of a line, a quote left open in it being an error as it
+    /// hides the delimiter
+    fn split_pair<'a>(&self, pair: &'a str) -> Result<Option<(&'a str, &'a str)>> {
+        match self.split_key_value(pair) {
+            Some(split) => Ok(Some(split)),
+            None => check_quotes(pair.trim()).map(|_| None),
+        }
+    }
+
+    /// Split a pair at the first delimiter which is not escaped or quoted in the key
+    fn split_key_value<'a>(&self, pair: &'a str) -> Option<(&'a str, &'a str)> {
+        let delimiter = self.options.key_to_value_delimiter.as_str();
+        find_unquoted(pair, delimiter).map(|i| (&pair[..i], &pair[i + delimiter.len()..]))
+    }
+
+    /// Insert the value read at `line_number`, and its meta data if enabled
+    fn insert_value(
+        &mut self,
+        dotted_key: &str,
+        value: Node,
+        line_number: i64,
+        trails: &mut Trails,
+    ) -> Result<()> {
+        let meta = match self.options.meta_mode {
+            MetaMode::Off => None,
+            _ => Some(self.meta.mirror(&value, line_number)),
+        };
+        let segments = parse_key_path(dotted_key, &self.options.path_separator)?;
+        let segments = self.grouped(segments, trails)?;
+        let options = &self.options;
+        match meta {
+            Some(meta) => {
+                insert_segments(
+                    &mut self.values,
+                    segments.clone(),
+                    value,
+                    options,
+                    &mut trails.values,
+                )?;
+                insert_segments(
+                    &mut self.meta.lines,
+                    segments,
+                    meta,
+                    options,
+                    &mut trails.meta,
+                )
+            }
+            None => insert_segments(
+                &mut self.values,
+                segments,
+                value,
+                options,
+                &mut trails.values,
+            ),
+        }
+    }
 
-                let value = Node::Leaf(value.to_string().into());
+    /// Address the current element of a group, ie `it.x` as `it[-1].x`, adding
+    /// a new element if needed
+    fn grouped(
+        &mut self,
+        segments: Vec<KeySegment>,
+        trails: &mut Trails,
+    ) -> Result<Vec<KeySegment>> {
+        let group_keys = self.group_keys.as_ref().map_err(Error::clone)?;
+        let found = group_keys
+            .iter()
+            .map(|group| (group, true))
+            .chain(self.groups.iter().map(|group| (group, false)))
+            .find(|(group, _)| {
+                segments.len() > group.len()
+                    && segments[..group.len()] == group[..]
+                    && matches!(segments[group.len()], KeySegment::Key(_))
+            });
+        let Some((group, auto)) = found else {
+            return Ok(segments);
+        };
+        let group = group.clone();
 
-                let keys: Vec<String> = dotted_key.split(".").map(|s| s.to_string()).collect();
+        let fields = &segments[group.len()..];
+        let new_element = match node_at_path(&mut self.values, &group) {
+            None => true,
+            Some(Node::Array(a)) => match a.last_mut() {
+                // a field of the current element repeats
+                Some(Node::Dict(m)) => auto && node_at_path(m, fields).is_some(),
+                _ => true,
+            },
+            // not an array of objects, the key is left as it is
+            Some(_) => return Ok(segments),
+        };
+        if new_element {
+            self.push_group_element(&group, trails)?;
+        }
+        let mut grouped = group;
+        grouped.push(KeySegment::Index(-1));
+        grouped.extend_from_slice(fields);
+        Ok(grouped)
+    }
 
-                // let mut h = HashMap::new();
+    /// Append an empty object to each array of a scope, ie `it[]`, so that the
+    /// lines of the scope all go to this object. Returns the key with the
+    /// index of the appended objects, ie `it[2]`
+    fn resolve_appends(&mut self, dotted_key: &str, trails: &mut Trails) -> Result<String> {
+        let mut resolved = String::new();
+        let mut rest = dotted_key;
+        while let Some(i) = find_unquoted(rest, "[]") {
+            resolved += &rest[..i];
+            let segments = parse_key_path(&self.scoped(&resolved), &self.options.path_separator)?;
+            self.push_group_element(&segments, trails)?;
+            let Some(Node::Array(a)) = node_at_path(&mut self.values, &segments) else {
+                return Err(Error::NotAnArray(resolved));
+            };
+            resolved += &format!("[{}]", a.len() - 1);
+            rest = &rest[i + 2..];
+        }
+        Ok(resolved + rest)
+    }
 
-                let h = string_to_dict(keys, value);
-                // self.values.extend(h);
+    /// Resolve again the scopes whose element is gone, ie `it[0]` for `it[]`
+    /// after a flush, appending a new element to their array
+    fn refresh_scopes(&mut self, trails: &mut Trails) -> Result<()> {
+        let separator = &self.options.path_separator;
+        let mut key = String::new();
+        let mut stale = None;
+        for (i, scope) in self.scopes.iter().enumerate() {
+            if !key.is_empty() {
+                key += separator;
+            }
+            key += &scope.resolved;
+            if find_unquoted(&scope.dotted_key, "[]").is_some() {
+                let segments = parse_key_path(&key, separator)?;
+                if node_at_path(&mut self.values, &segments).is_none() {
+                    stale = Some(i);
+                    break;
+                }
+            }
+        }
+        let Some(stale) = stale else {
+            return Ok(());
+        };
 
-                extend_hashmap(&mut self.values, h, &self.options)?;
+        // the following scopes are resolved again too, being nested in it
+        let scopes = self.scopes.split_off(stale);
+        for scope in &scopes {
+            match self.resolve_appends(&scope.dotted_key, trails) {
+                Ok(resolved) => self.scopes.push(Scope {
+                    dotted_key: scope.dotted_key.clone(),
+                    resolved,
+                }),
+                Err(e) => {
+                    self.scopes.truncate(stale);
+                    self.scopes.extend(scopes.iter().cloned());
+                    return Err(e);
+                }
             }
+        }
+        Ok(())
+    }
 
-            // self.values.insert(dotted_key.to_string(), value);
+    /// Append an empty object to the array of a group
+    fn push_group_element(&mut self, group: &[KeySegment], trails: &mut Trails) -> Result<()> {
+        let mut segments = group.to_vec();
+        segments.push(KeySegment::Append);
+        let element = Node::Dict(MapNodes::new());
+        let options = &self.options;
+        insert_at_path(
+            &mut self.values,
+            &segments,
+            element.clone(),
+            options,
+            &mut trails.values,
+        )?;
+        if options.meta_mode != MetaMode::Off {
+            insert_at_path(
+                &mut self.meta.lines,
+                &segments,
+                element,
+                options,
+                &mut trails.meta,
+            )?;
         }
         Ok(())
     }
 
+    /// The values to write, with their meta data if enabled
+    fn rendered_values(&self) -> MapNodes {
+        self.meta.render(
+            &self.values,
+            self.options.meta_mode,
+            self.options.time_format,
+        )
+    }
+
     /// Used for testing
-    pub fn _get_json_object(&self) -> serde_json::Value {
-        serde_json::to_value(&self.values).unwrap()
+    pub(crate) fn _get_json_object(&self) -> serde_json::Value {
+        serde_json::to_value(self.rendered_values()).unwrap()
+    }
+
+    /// The values as written, in the key order
+    fn output_values(&self) -> MapNodes {
+        let mut values = Node::Dict(self.rendered_values());
+        self.arrange(&[], &mut values);
+        let Node::Dict(values) = values else {
+            unreachable!()
+        };
+        values
+    }
+
+    /// Transpose and sort the keys of `node` as written, `node` being found at
+    /// the key `path`
+    fn arrange(&self, path: &[KeySegment], node: &mut Node) {
+        for dotted_key in &self.options.transpose_keys {
+            let Ok(segments) = parse_key_path(dotted_key, &self.options.path_separator) else {
+                continue;
+            };
+            let target = match (segments.strip_prefix(path), &mut *node) {
+                (Some([]), node) => Some(node),
+                (Some(relative), Node::Dict(m)) => node_at_path(m, relative),
+                _ => None,
+            };
+            if let Some(target) = target {
+                target.transpose();
+            }
+        }
+        if self.options.key_order == KeyOrder::Sorted {
+            node.sort_keys();
+        }
+    }
+
+    /// The values read so far, without their meta data
+    pub fn values(&self) -> &MapNodes {
+        &self.values
     }
 
     pub fn get_json(&self) -> String {
-        let json = serde_json::to_string(&self.values).unwrap();
-        json
+        serde_json::to_string(&self.output_values()).unwrap()
+    }
+}
+
+/// Insert a value at a dotted key, ie `a.b[0].c`
+fn insert_dotted(
+    h: &mut MapNodes,
+    dotted_key: &str,
+    value: Node,
+    options: &EngineOptions,
+    trail: &mut Trail,
+) -> Result<()> {
+    let segments = parse_key_path(dotted_key, &options.path_separator)?;
+    insert_segments(h, segments, value, options, trail)
+}
+
+/// Insert a value at a parsed dotted key
+fn insert_segments(
+    h: &mut MapNodes,
+    segments: Vec<KeySegment>,
+    value: Node,
+    options: &EngineOptions,
+    trail: &mut Trail,
+) -> Result<()> {
+    if let ([KeySegment::Key(k)], Node::Dict(h2)) = (segments.as_slice(), &value) {
+        // a json dict without key is grafted at the root
+        if k.is_empty() {
+            return extend_hashmap(h, h2.clone(), options, trail);
+        }
+    }
+
+    if !is_plain_path(&segments) {
+        return insert_at_path(h, &segments, value, options, trail);
     }
+
+    let keys: Vec<String> = segments
+        .into_iter()
+        .filter_map(|s| match s {
+            KeySegment::Key(k) => Some(k),
+            _ => None,
+        })
+        .collect();
+
+    let h2 = string_to_dict(keys, value);
+    extend_hashmap(h, h2, options, trail)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::engine::engine_options::RepeatedLeafOverride;
     use serde_json::json;
 
     #[test]
@@ -399,4 +1390,1218 @@ mod tests {
         assert_eq!(json, correct_json);
         Ok(())
     }
+
+    fn merge_json(
+        existing: serde_json::Value,
+        to_insert: serde_json::Value,
+        options: &EngineOptions,
+    ) -> Result<serde_json::Value> {
+        let mut h: MapNodes = serde_json::from_value(existing).unwrap();
+        let h2: MapNodes = serde_json::from_value(to_insert).unwrap();
+        extend_hashmap(&mut h, h2, options, &mut Trail::default())?;
+        Ok(h._json_object())
+    }
+
+    #[test]
+    fn test_merge_leaf_leaf() -> anyhow::Result<()> {
+        let options = EngineOptions::new();
+        let json = merge_json(json!({"a": 1}), json!({"a": 2}), &options)?;
+        assert_eq!(json, json!({"a": [1, 2]}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_leaf_dict() -> anyhow::Result<()> {
+        let options = EngineOptions::new();
+        let json = merge_json(json!({"a": 1}), json!({"a": {"b": 2}}), &options)?;
+        assert_eq!(json, json!({"a": {"value": 1, "b": 2}}));
+
+        let json = merge_json(json!({"a": 1}), json!({"a": {"value": 2}}), &options)?;
+        assert_eq!(json, json!({"a": {"value": [1, 2]}}));
+
+        let options = EngineOptions::new().with_how_to_leaf_in_dict(HowToLeafInDict::GenerateError);
+        let e = merge_json(json!({"a": 1}), json!({"a": {"b": 2}}), &options);
+        assert!(matches!(e, Err(Error::HowToLeafInDict)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_leaf_array() -> anyhow::Result<()> {
+        let options = EngineOptions::new();
+        let json = merge_json(json!({"a": 1}), json!({"a": [2, 3]}), &options)?;
+        assert_eq!(json, json!({"a": [1, 2, 3]}));
+
+        let options = EngineOptions::new().with_how_to_array_in_array(HowToArrayInArray::Nest);
+        let json = merge_json(json!({"a": 1}), json!({"a": [2, 3]}), &options)?;
+        assert_eq!(json, json!({"a": [1, [2, 3]]}));
+
+        let options =
+            EngineOptions::new().with_how_to_array_in_array(HowToArrayInArray::GenerateError);
+        let e = merge_json(json!({"a": 1}), json!({"a": [2, 3]}), &options);
+        assert!(matches!(e, Err(Error::HowToArrayInArray)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dict_leaf() -> anyhow::Result<()> {
+        let options = EngineOptions::new();
+        let json = merge_json(json!({"a": {"b": 1}}), json!({"a": 2}), &options)?;
+        assert_eq!(json, json!({"a": {"b": 1, "value": 2}}));
+
+        let json = merge_json(json!({"a": {"value": 1}}), json!({"a": 2}), &options)?;
+        assert_eq!(json, json!({"a": {"value": [1, 2]}}));
+
+        let options = EngineOptions::new().with_how_to_leaf_in_dict(HowToLeafInDict::GenerateError);
+        let e = merge_json(json!({"a": {"b": 1}}), json!({"a": 2}), &options);
+        assert!(matches!(e, Err(Error::HowToLeafInDict)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dict_dict() -> anyhow::Result<()> {
+        let options = EngineOptions::new();
+        let json = merge_json(
+            json!({"a": {"b": 1, "c": {"d": 2}}}),
+            json!({"a": {"c": {"e": 3}}}),
+            &options,
+        )?;
+        assert_eq!(json, json!({"a": {"b": 1, "c": {"d": 2, "e": 3}}}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_dict_array() -> anyhow::Result<()> {
+        let e = merge_json(
+            json!({"a": {"b": 1}}),
+            json!({"a": [2]}),
+            &EngineOptions::new(),
+        );
+        assert!(matches!(e, Err(Error::HowToDictInArray)));
+
+        let options =
+            EngineOptions::new().with_how_to_dict_in_array(HowToDictInArray::MergeDictInArray);
+        let json = merge_json(json!({"a": {"b": 1}}), json!({"a": [2]}), &options)?;
+        assert_eq!(json, json!({"a": [{"b": 1}, 2]}));
+
+        let options =
+            EngineOptions::new().with_how_to_dict_in_array(HowToDictInArray::MakeArrayAsDictValue);
+        let json = merge_json(json!({"a": {"b": 1}}), json!({"a": [2]}), &options)?;
+        assert_eq!(json, json!({"a": {"b": 1, "array": [2]}}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_array_leaf() -> anyhow::Result<()> {
+        let options = EngineOptions::new();
+        let json = merge_json(json!({"a": [1, 2]}), json!({"a": 3}), &options)?;
+        assert_eq!(json, json!({"a": [1, 2, 3]}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_array_dict() -> anyhow::Result<()> {
+        let e = merge_json(
+            json!({"a": [1]}),
+            json!({"a": {"b": 2}}),
+            &EngineOptions::new(),
+        );
+        assert!(matches!(e, Err(Error::HowToDictInArray)));
+
+        let options =
+            EngineOptions::new().with_how_to_dict_in_array(HowToDictInArray::MergeDictInArray);
+        let json = merge_json(json!({"a": [1]}), json!({"a": {"b": 2}}), &options)?;
+        assert_eq!(json, json!({"a": [1, {"b": 2}]}));
+
+        let options =
+            EngineOptions::new().with_how_to_dict_in_array(HowToDictInArray::MakeArrayAsDictValue);
+        let json = merge_json(json!({"a": [1]}), json!({"a": {"b": 2}}), &options)?;
+        assert_eq!(json, json!({"a": {"array": [1], "b": 2}}));
+
+        // a failed merge leaves the array as it was
+        let options = options.with_how_to_repeated_leaf(HowToRepeatedLeaf::GenerateError);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a[]:1")?;
+        assert!(matches!(
+            engine.add_line(";a.array:3"),
+            Err(Error::RepeatedLeaf(_))
+        ));
+        assert_eq!(engine._get_json_object(), json!({"a": [1]}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_array_array() -> anyhow::Result<()> {
+        let options = EngineOptions::new();
+        let json = merge_json(json!({"a": [1]}), json!({"a": [2, 3]}), &options)?;
+        assert_eq!(json, json!({"a": [1, 2, 3]}));
+
+        let options = EngineOptions::new().with_how_to_array_in_array(HowToArrayInArray::Nest);
+        let json = merge_json(json!({"a": [1]}), json!({"a": [2, 3]}), &options)?;
+        assert_eq!(json, json!({"a": [1, [2, 3]]}));
+
+        let options =
+            EngineOptions::new().with_how_to_array_in_array(HowToArrayInArray::GenerateError);
+        let e = merge_json(json!({"a": [1]}), json!({"a": [2, 3]}), &options);
+        assert!(matches!(e, Err(Error::HowToArrayInArray)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_leaf_in_dict() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_verbosity(10));
+
+        engine.add_line(";a.b:1")?;
+        engine.add_line(";a:2")?;
+        let json = engine._get_json_object();
+        let correct_json = json!({"a": {"b": 1, "value": 2}});
+        assert_eq!(json, correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_dict_in_array_as_dict_value() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_how_to_dict_in_array(HowToDictInArray::MakeArrayAsDictValue)
+            .with_verbosity(10);
+        let mut engine = Engine::new(options);
+
+        engine.add_line(";a:1")?;
+        engine.add_line(";a:2")?;
+        engine.add_line(";a.b:4")?;
+        let json = engine._get_json_object();
+        let correct_json = json!({"a": {"array": [1, 2], "b": 4}});
+        assert_eq!(json, correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_leaf_policies() -> anyhow::Result<()> {
+        let cases = [
+            (
+                HowToRepeatedLeaf::AppendArray,
+                json!({"a": [1, 2, 3], "b": 4}),
+            ),
+            (HowToRepeatedLeaf::LastWins, json!({"a": 3, "b": 4})),
+            (HowToRepeatedLeaf::FirstWins, json!({"a": 1, "b": 4})),
+            (
+                HowToRepeatedLeaf::AlwaysArray,
+                json!({"a": [1, 2, 3], "b": [4]}),
+            ),
+        ];
+        for (how, correct_json) in cases {
+            let mut engine = Engine::new(EngineOptions::new().with_how_to_repeated_leaf(how));
+            engine.add_line(";a:1")?;
+            engine.add_line(";a:2")?;
+            engine.add_line(";a:3")?;
+            engine.add_line(";b:4")?;
+            assert_eq!(engine._get_json_object(), correct_json);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_leaf_error() -> anyhow::Result<()> {
+        let options =
+            EngineOptions::new().with_how_to_repeated_leaf(HowToRepeatedLeaf::GenerateError);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a.b:1")?;
+        let e = engine.add_line(";a.b:2");
+        assert!(matches!(e, Err(Error::RepeatedLeaf(ref k)) if k == "a.b"));
+        assert_eq!(engine._get_json_object(), json!({"a": {"b": 1}}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_always_array_nested() -> anyhow::Result<()> {
+        let options =
+            EngineOptions::new().with_how_to_repeated_leaf(HowToRepeatedLeaf::AlwaysArray);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a.b:1")?;
+        engine.add_line(";a.c.d:2")?;
+        engine.add_line(";a.c.d:3")?;
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"a": {"b": [1], "c": {"d": [2, 3]}}})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_repeated_leaf_overrides() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_repeated_leaf_override(RepeatedLeafOverride::new(
+                "status.*",
+                HowToRepeatedLeaf::LastWins,
+            ))
+            .with_repeated_leaf_override(RepeatedLeafOverride::new(
+                "**.id",
+                HowToRepeatedLeaf::FirstWins,
+            ))
+            .with_repeated_leaf_override(RepeatedLeafOverride::new(
+                "status.**",
+                HowToRepeatedLeaf::GenerateError,
+            ));
+        let mut engine = Engine::new(options);
+        engine.add_line(";status.state:init")?;
+        engine.add_line(";status.state:run")?;
+        engine.add_line(";status.job.id:1")?;
+        engine.add_line(";status.job.id:2")?;
+        engine.add_line(";samples:1")?;
+        engine.add_line(";samples:2")?;
+        let e = engine.add_line(";status.job.name:a");
+        assert!(e.is_ok());
+        let e = engine.add_line(";status.job.name:b");
+        assert!(matches!(e, Err(Error::RepeatedLeaf(_))));
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"status": {"state": "run", "job": {"id": 1, "name": "a"}}, "samples": [1, 2]})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_key_order() -> anyhow::Result<()> {
+        let lines = [";b:1", ";a.z:2", ";c:3", ";a.y:4", ";b:5"];
+
+        let mut engine = Engine::new(EngineOptions::new());
+        for line in lines {
+            engine.add_line(line)?;
+        }
+        assert_eq!(engine.get_json(), r#"{"b":[1,5],"a":{"z":2,"y":4},"c":3}"#);
+
+        let mut engine = Engine::new(EngineOptions::new().with_key_order(KeyOrder::Sorted));
+        for line in lines {
+            engine.add_line(line)?;
+        }
+        assert_eq!(engine.get_json(), r#"{"a":{"y":4,"z":2},"b":[1,5],"c":3}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_array_of_objects() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_verbosity(10));
+        engine.add_line(";points[].x:1")?;
+        engine.add_line(";points[-1].y:2")?;
+        engine.add_line(";points[].x:3")?;
+        engine.add_line(";points[-1].y:4")?;
+        engine.add_line(";points[0].z:5")?;
+        let correct_json = json!({"points": [{"x": 1, "y": 2, "z": 5}, {"x": 3, "y": 4}]});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_array_append() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_verbosity(10));
+        engine.add_line(";a.b[]:1")?;
+        engine.add_line(";a.b[]:2")?;
+        engine.add_line(";a.m[][]:3")?;
+        engine.add_line(";a.m[0][]:4")?;
+        engine.add_line(";a.m[][]:5")?;
+        let correct_json = json!({"a": {"b": [1, 2], "m": [[3, 4], [5]]}});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_array_index_errors() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_verbosity(10));
+        let e = engine.add_line(";a[0]:1");
+        assert!(matches!(e, Err(Error::IndexOutOfRange(_))));
+
+        engine.add_line(";a[]:1")?;
+        let e = engine.add_line(";a[1]:2");
+        assert!(matches!(e, Err(Error::IndexOutOfRange(_))));
+        let e = engine.add_line(";a[-2]:2");
+        assert!(matches!(e, Err(Error::IndexOutOfRange(_))));
+
+        engine.add_line(";b:1")?;
+        let e = engine.add_line(";b[]:2");
+        assert!(matches!(e, Err(Error::NotAnArray(ref k)) if k == "b"));
+
+        let e = engine.add_line(";c[x]:2");
+        assert!(matches!(e, Err(Error::InvalidKey(_))));
+
+        assert_eq!(engine._get_json_object(), json!({"a": [1], "b": 1}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_array_index_merge() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_repeated_leaf_override(RepeatedLeafOverride::new(
+            "a.v",
+            HowToRepeatedLeaf::LastWins,
+        ));
+        let mut engine = Engine::new(options);
+        engine.add_line(";a[].v:1")?;
+        engine.add_line(";a[0].v:2")?;
+        engine.add_line(";a[0].w:3")?;
+        engine.add_line(";a[0].w:4")?;
+        let correct_json = json!({"a": [{"v": 2, "w": [3, 4]}]});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_array_index_set() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_meta_mode(MetaMode::Subtree);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a[]:1")?;
+        engine.add_line(";a[]:2")?;
+        engine.add_line(";a[0]:5")?;
+        engine.add_line(";a[-1]:6")?;
+        let mut json = engine._get_json_object();
+        strip_times(&mut json);
+        let correct_json = json!({
+            "a": [5, 6],
+            "_meta": {"a": [{"line": 3}, {"line": 4}]}
+        });
+        assert_eq!(json, correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_escaped_and_quoted_keys() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_verbosity(10));
+        engine.add_line(r";libs.libc\.so\.6:1")?;
+        engine.add_line(r#";hosts."10.0.0.1".latency:12"#)?;
+        let correct_json =
+            json!({"libs": {"libc.so.6": 1}, "hosts": {"10.0.0.1": {"latency": 12}}});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_unterminated_quote() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        let e = engine.add_line(r#";5" tv:1"#);
+        assert!(matches!(e, Err(Error::InvalidKey(_))));
+        // a line without delimiter is ignored
+        engine.add_line(";5 tv")?;
+
+        let mut engine = Engine::new(EngineOptions::new().with_pair_separator(";".to_string()));
+        let e = engine.add_line(r#";a:1;5" tv:2"#);
+        assert!(matches!(e, Err(Error::InvalidKey(_))));
+        assert_eq!(engine._get_json_object(), json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_path_separator() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_path_separator("/".to_string())
+            .with_repeated_leaf_override(RepeatedLeafOverride::new(
+                "hosts/*/up",
+                HowToRepeatedLeaf::LastWins,
+            ));
+        let mut engine = Engine::new(options);
+        engine.add_line(";hosts/10.0.0.1/up:0")?;
+        engine.add_line(";hosts/10.0.0.1/up:1")?;
+        engine.add_line(";hosts/10.0.0.1/ports[]:22")?;
+        let correct_json = json!({"hosts": {"10.0.0.1": {"up": 1, "ports": [22]}}});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_line_prefix() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_line_prefix("@@J ".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";a:1")?;
+        engine.add_line("@@J b.c:2")?;
+        assert_eq!(engine._get_json_object(), json!({"b": {"c": 2}}));
+
+        let options = EngineOptions::new()
+            .with_line_prefix("[c2j]".to_string())
+            .with_key_to_value_delimiter("=".to_string())
+            .with_path_separator("/".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line("[c2j]a/b=x:1")?;
+        engine.add_line("[c2j]a/c[]=2")?;
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"a": {"b": "x:1", "c": [2]}})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_anywhere() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_prefix_location(PrefixLocation::Anywhere);
+        let mut engine = Engine::new(options);
+        engine.add_line("2026-10-01 12:00:00 INFO ;a.b:1")?;
+        engine.add_line(";a.c:2")?;
+        engine.add_line("no marker here")?;
+        assert_eq!(engine._get_json_object(), json!({"a": {"b": 1, "c": 2}}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_anywhere_leading_text() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_prefix_location(PrefixLocation::Anywhere)
+            .with_leading_text_key("log.header".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line("12:00:00 INFO ;a:1")?;
+        engine.add_line(";a:2")?;
+        engine.add_line("12:00:02 INFO ;a:3")?;
+        let correct_json =
+            json!({"a": [1, 2, 3], "log": {"header": ["12:00:00 INFO", "12:00:02 INFO"]}});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_prefix_after_regex() -> anyhow::Result<()> {
+        let regex = regex::Regex::new(r"^(\S+ \S+) INFO +")?;
+        let options = EngineOptions::new()
+            .with_prefix_location(PrefixLocation::AfterRegex(regex))
+            .with_leading_text_key("timestamp".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line("2026-10-01 12:00:00 INFO ;a:1")?;
+        engine.add_line("2026-10-01 12:00:01 DEBUG ;a:2")?;
+        engine.add_line(";a:3")?;
+        let correct_json = json!({"a": 1, "timestamp": "2026-10-01 12:00:00"});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_pair() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_pair_separator(";".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";temp:21.5;hum:40;volt:3.3")?;
+        engine.add_line(";temp:22;")?;
+        engine.add_line("; a.b:1 ; a.c:2")?;
+        let correct_json =
+            json!({"temp": [21.5, 22], "hum": 40, "volt": 3.3, "a": {"b": 1, "c": 2}});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_pair_separator() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_pair_separator(",".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";x:1,y:2")?;
+        engine.add_line(";msg:a;b")?;
+        let correct_json = json!({"x": 1, "y": 2, "msg": "a;b"});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_pair_atomic() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_pair_separator(";".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";a:1;a:2")?;
+
+        let e = engine.add_line(";b:1;a.c:3");
+        assert!(matches!(e, Err(Error::HowToDictInArray)));
+        let e = engine.add_line(";b:1;oops");
+        assert!(matches!(e, Err(Error::MissingDelimiter(ref p)) if p == "oops"));
+        let e = engine.add_line(";b:1;c[x]:1");
+        assert!(matches!(e, Err(Error::InvalidKey(_))));
+
+        assert_eq!(engine._get_json_object(), json!({"a": [1, 2]}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_pair_rollback() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_pair_separator(";".to_string())
+            .with_how_to_leaf_in_dict(HowToLeafInDict::MakeLeafAsDictValue)
+            .with_how_to_dict_in_array(HowToDictInArray::MakeArrayAsDictValue)
+            .with_meta_mode(MetaMode::Subtree);
+        let mut engine = Engine::new(options);
+        engine.add_line(";l:1;d.x:1;a[]:1;z[]:0")?;
+        let before = engine._get_json_object();
+
+        // each line changes the tree before failing on `z[5]`
+        for pairs in [
+            "l:2",
+            "l.y:2",
+            "a[]:2",
+            "a[0]:2",
+            "a.y:2",
+            "d:2",
+            "d.x.y:2",
+            "n.k:1;n.k:2",
+            r#"@json:{"d":{"x":2,"w":1},"a":[3]}"#,
+        ] {
+            let e = engine.add_line(&format!(";{};z[5]:1", pairs));
+            assert!(matches!(e, Err(Error::IndexOutOfRange(_))), "{}", pairs);
+            assert_eq!(engine._get_json_object(), before, "{}", pairs);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_multi_pair_many_lines() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_pair_separator(";".to_string())
+            .with_meta_mode(MetaMode::Inline);
+        let mut engine = Engine::new(options);
+        let lines = 5000;
+        for i in 0..lines {
+            engine.add_line(&format!(";n:{};p.x:{};p.y:{}", i, i, i))?;
+        }
+        assert!(engine.add_line(";n:1;p.x.z:1").is_err());
+        let Some(Node::Dict(p)) = engine.values().get("p") else {
+            panic!("p is not a dict")
+        };
+        for key in ["x", "y"] {
+            assert!(matches!(p.get(key), Some(Node::Array(a)) if a.len() == lines));
+        }
+        assert!(matches!(engine.values().get("n"), Some(Node::Array(a)) if a.len() == lines));
+        Ok(())
+    }
+
+    #[test]
+    fn test_bool_null() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_true_word("on".to_string())
+            .with_false_word("off".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";led:on")?;
+        engine.add_line(";fan:false")?;
+        engine.add_line(";err:null")?;
+        engine.add_line(";name:True")?;
+        let correct_json = json!({"led": true, "fan": false, "err": null, "name": "True"});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_annotations() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";zip@str:01234")?;
+        engine.add_line(";id@str:42")?;
+        engine.add_line(";ratio@f64:1")?;
+        engine.add_line(";versions[]@s:1.10")?;
+        engine.add_line(r";mail\@host:1")?;
+        let e = engine.add_line(";n@int:x");
+        assert!(matches!(e, Err(Error::InvalidValue(_))));
+        // not a known type, the `@` is part of the key
+        engine.add_line(";user@host:1")?;
+        engine.add_line(";n@u2:1")?;
+        let correct_json = json!({
+            "zip": "01234", "id": "42", "ratio": 1.0, "versions": ["1.10"], "mail@host": 1,
+            "user@host": 1, "n@u2": 1
+        });
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_infer() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_infer_types(false));
+        engine.add_line(";a:1")?;
+        engine.add_line(";b@int:2")?;
+        engine.add_line(";c:true")?;
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"a": "1", "b": 2, "c": "true"})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_values() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(r#";pos@json:{"x":1,"y":2}"#)?;
+        engine.add_line(r#";pos@json:{"y":3,"z":{"a":[1,2]}}"#)?;
+        engine.add_line(";list@json:[1,2,3]")?;
+        engine.add_line(";list@json:[4]")?;
+        engine.add_line(r#";@json:{"root":true}"#)?;
+        let e = engine.add_line(";bad@json:{x}");
+        assert!(matches!(e, Err(Error::InvalidValue(_))));
+        let correct_json = json!({
+            "pos": {"x": 1, "y": [2, 3], "z": {"a": [1, 2]}},
+            "list": [1, 2, 3, 4],
+            "root": true
+        });
+        assert_eq!(engine._get_json_object(), correct_json);
+        assert_eq!(
+            engine.get_json(),
+            r#"{"pos":{"x":1,"y":[2,3],"z":{"a":[1,2]}},"list":[1,2,3,4],"root":true}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_quoted_values() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_pair_separator(";".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";msg:\"  hello\\n world \"\n")?;
+        engine.add_line(r#";zip:"01234";sep:"a;b";"k:1":"x:y""#)?;
+        let correct_json =
+            json!({"msg": "  hello\n world ", "zip": "01234", "sep": "a;b", "k:1": "x:y"});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_multi_line_values(true));
+        engine.add_line(";a:1\n")?;
+        engine.add_line(";trace:<<EOF\n")?;
+        engine.add_line("#0 main()\n")?;
+        engine.add_line(";a:2\n")?;
+        engine.add_line("  #1 start()\r\n")?;
+        engine.add_line("EOF\n")?;
+        engine.add_line(";a:3\n")?;
+        engine.finish()?;
+        let correct_json = json!({"a": [1, 3], "trace": "#0 main()\n;a:2\n  #1 start()"});
+        assert_eq!(engine._get_json_object(), correct_json);
+
+        // a value printed by chance takes no line when disabled
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";op:<<operator")?;
+        engine.add_line(";a:1")?;
+        engine.finish()?;
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"op": "<<operator", "a": 1})
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_typed() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_pair_separator(";".to_string())
+            .with_multi_line_values(true);
+        let mut engine = Engine::new(options);
+        engine.add_line(";n:1;m@json:<<END")?;
+        engine.add_line("[[1, 2],")?;
+        engine.add_line(" [3, 4]]")?;
+        engine.add_line("END")?;
+        engine.add_line(";s:<<EOF;t:1")?;
+        engine.add_line(";empty:<<EOF")?;
+        engine.add_line("EOF")?;
+        let correct_json =
+            json!({"n": 1, "m": [[1, 2], [3, 4]], "s": "<<EOF", "t": 1, "empty": ""});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_block_unterminated() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_multi_line_values(true));
+        engine.add_line(";trace:<<EOF")?;
+        engine.add_line("#0 main()")?;
+        let e = engine.finish();
+        assert!(matches!(e, Err(Error::UnterminatedBlock(ref k)) if k == "trace"));
+        assert_eq!(engine._get_json_object(), json!({}));
+        Ok(())
+    }
+
+    /// The meta data of the json, without the arrival times
+    fn strip_times(json: &mut serde_json::Value) {
+        match json {
+            serde_json::Value::Object(m) => {
+                if let Some(t) = m.remove("t") {
+                    assert!(t.is_number(), "not an epoch: {}", t);
+                }
+                m.values_mut().for_each(strip_times);
+            }
+            serde_json::Value::Array(a) => a.iter_mut().for_each(strip_times),
+            _ => {}
+        }
+    }
+
+    #[test]
+    fn test_meta_inline() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_meta_mode(MetaMode::Inline)
+            .with_pair_separator(";".to_string())
+            .with_multi_line_values(true);
+        let mut engine = Engine::new(options);
+        engine.add_line("starting")?;
+        engine.add_line(";a:1;b.c:x")?;
+        engine.add_line(";a:2")?;
+        assert!(engine.add_line(";d:1;a.x:2").is_err());
+        engine.add_line(";s:<<EOF")?;
+        engine.add_line("text")?;
+        engine.add_line("EOF")?;
+        let mut json = engine._get_json_object();
+        strip_times(&mut json);
+        let correct_json = json!({
+            "a": [{"v": 1, "line": 2}, {"v": 2, "line": 3}],
+            "b": {"c": {"v": "x", "line": 2}},
+            "s": {"v": "text", "line": 5}
+        });
+        assert_eq!(json, correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_values_atomic() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_meta_mode(MetaMode::Subtree);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a.x:1")?;
+        // the conflict on `a` comes after `b` is inserted
+        assert!(engine.add_line(r#";@json:{"b":1,"a":[2]}"#).is_err());
+        assert!(engine.add_line(r#";c@json:{"d":1,"a":[2]}"#).is_ok());
+        let mut json = engine._get_json_object();
+        strip_times(&mut json);
+        let correct_json = json!({
+            "a": {"x": 1},
+            "c": {"d": 1, "a": [2]},
+            "_meta": {"a": {"x": {"line": 1}}, "c": {"d": {"line": 3}, "a": [{"line": 3}]}}
+        });
+        assert_eq!(json, correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_meta_subtree() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_meta_mode(MetaMode::Subtree);
+        let mut engine = Engine::new(options);
+        engine.add_line(r#";@json:{"a":{"b":[1,2]}}"#)?;
+        engine.add_line(";a.c:3")?;
+        let mut json = engine._get_json_object();
+        strip_times(&mut json);
+        let correct_json = json!({
+            "a": {"b": [1, 2], "c": 3},
+            "_meta": {"a": {"b": [{"line": 1}, {"line": 1}], "c": {"line": 2}}}
+        });
+        assert_eq!(json, correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_repeated_key() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_record_boundary(RecordBoundary::RepeatedKey("id".to_string()))
+            .with_pair_separator(";".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";id:1;a:1")?;
+        engine.add_line(";a:2")?;
+        assert_eq!(engine._get_json_object(), json!({"id": 1, "a": [1, 2]}));
+        engine.add_line(";a:3;id:2")?;
+        assert_eq!(engine._get_json_object(), json!({"a": 3, "id": 2}));
+        assert_eq!(
+            engine.take_outputs(),
+            vec![Output::Json(r#"{"id":1,"a":[1,2]}"#.to_string())]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_marker() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_record_boundary(RecordBoundary::MarkerKey("end".to_string()))
+            .with_pair_separator(";".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";a:1")?;
+        engine.add_line(";b:1;end:")?;
+        assert_eq!(engine._get_json_object(), json!({}));
+        engine.add_line(";a:2")?;
+        engine.add_line("some output")?;
+        engine.add_line("")?;
+        assert_eq!(engine._get_json_object(), json!({"a": 2}));
+        engine.add_line(";end:1")?;
+        assert_eq!(engine._get_json_object(), json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_lines() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_record_boundary(RecordBoundary::BlankLine);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a:1\n")?;
+        engine.add_line("some output\n")?;
+        assert_eq!(engine._get_json_object(), json!({"a": 1}));
+        engine.add_line(" \n")?;
+        assert_eq!(engine._get_json_object(), json!({}));
+
+        let options = EngineOptions::new()
+            .with_record_boundary(RecordBoundary::UnmatchedLine)
+            .with_multi_line_values(true);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a:<<EOF\n")?;
+        engine.add_line("some output\n")?;
+        engine.add_line("EOF\n")?;
+        assert_eq!(engine._get_json_object(), json!({"a": "some output"}));
+        engine.add_line("some output\n")?;
+        assert_eq!(engine._get_json_object(), json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";a:1")?;
+        assert_eq!(engine.get_snapshot_json(), r#"{"a":1}"#);
+        engine.add_line(";b:1")?;
+        engine.add_line(";stdout.loop:snapshot")?;
+        assert_eq!(engine.get_snapshot_json(), r#"{"a":1,"b":1}"#);
+        assert_eq!(engine._get_json_object(), json!({"a": 1, "b": 1}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_snapshot_changes_only() -> anyhow::Result<()> {
+        let options = EngineOptions::new().with_snapshot_changes_only(true);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a:1")?;
+        engine.add_line(";s.x:1")?;
+        assert_eq!(engine.get_snapshot_json(), r#"{"a":1,"s":{"x":1}}"#);
+        engine.add_line(";s.y:2")?;
+        engine.add_line(";b:1")?;
+        assert_eq!(engine.get_snapshot_json(), r#"{"s":{"y":2},"b":1}"#);
+        assert_eq!(engine.get_snapshot_json(), r#"{}"#);
+        engine.add_line(";stdout.loop:clear")?;
+        engine.add_line(";b:1")?;
+        assert_eq!(engine.get_snapshot_json(), r#"{"b":1}"#);
+        engine.add_line(";s.x:1")?;
+        engine.add_line(";s.y:1")?;
+        engine.get_snapshot_json();
+        engine.add_line(";!delete:b")?;
+        engine.add_line(";!delete:s.x")?;
+        assert_eq!(engine.get_snapshot_json(), r#"{"s":{"x":null},"b":null}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";a.b:1")?;
+        engine.add_line(";a.c:2")?;
+        engine.add_line(";l:1")?;
+        engine.add_line(";l:2")?;
+        engine.add_line(";l:3")?;
+        engine.add_line(";!delete:a.b")?;
+        engine.add_line(";!set:a.c=3")?;
+        engine.add_line(";!set:n@str=4")?;
+        engine.add_line(";!push:l=4")?;
+        engine.add_line(";!push:m.x=1")?;
+        engine.add_line(";!pop:l\n")?;
+        engine.add_line(";!delete:l[0]")?;
+        let correct_json = json!({"a": {"c": 3}, "l": [2, 3], "n": "4", "m": {"x": [1]}});
+        assert_eq!(engine._get_json_object(), correct_json);
+
+        engine.add_line(";!rename:a.c=m.x")?;
+        engine.add_line(";!rename:l=z")?;
+        let correct_json = json!({"a": {}, "n": "4", "m": {"x": [1, 3]}, "z": [2, 3]});
+        assert_eq!(engine._get_json_object(), correct_json);
+
+        engine.add_line(";!emit:z")?;
+        assert_eq!(
+            engine.take_outputs(),
+            vec![Output::Json("[2,3]".to_string())]
+        );
+        engine.add_line(";!clear")?;
+        assert_eq!(engine._get_json_object(), json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_emit_as_written() -> anyhow::Result<()> {
+        let emitted = |engine: &mut Engine| -> Vec<serde_json::Value> {
+            let outputs = engine.take_outputs().into_iter();
+            let jsons = outputs.filter_map(|output| match output {
+                Output::Json(json) => serde_json::from_str(&json).ok(),
+                _ => None,
+            });
+            jsons
+                .map(|mut json| {
+                    strip_times(&mut json);
+                    json
+                })
+                .collect()
+        };
+
+        let options = EngineOptions::new()
+            .with_meta_mode(MetaMode::Inline)
+            .with_transpose_key("s.it".to_string())
+            .with_transpose_key("it".to_string());
+        let mut engine = Engine::new(options);
+        for line in [";s.it.x:1", ";s.it.x:2", ";!emit:s", ";it.x:3", ";!emit:it"] {
+            engine.add_line(line)?;
+        }
+        let correct_jsons = vec![
+            json!({"it": [{"x": {"v": 1, "line": 1}}, {"x": {"v": 2, "line": 2}}]}),
+            json!([{"x": {"v": 3, "line": 4}}]),
+        ];
+        assert_eq!(emitted(&mut engine), correct_jsons);
+
+        let options = EngineOptions::new().with_meta_mode(MetaMode::Subtree);
+        let mut engine = Engine::new(options);
+        for line in [";s.a:1", ";s.l[]:2", ";!emit:s.l", ";!emit:s"] {
+            engine.add_line(line)?;
+        }
+        let correct_jsons = vec![json!([2]), json!({"a": 1, "_meta": {"a": {"line": 1}}})];
+        assert_eq!(emitted(&mut engine), correct_jsons);
+        Ok(())
+    }
+
+    #[test]
+    fn test_outputs() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";a:1")?;
+        engine.add_line(";!snapshot")?;
+        engine.add_line(";b:1")?;
+        engine.add_line(";stdout.loop:flush")?;
+        engine.add_line(";c:1")?;
+        assert_eq!(
+            engine.take_outputs(),
+            vec![
+                Output::Json(r#"{"a":1}"#.to_string()),
+                Output::Json(r#"{"a":1,"b":1}"#.to_string())
+            ]
+        );
+        assert!(engine.take_outputs().is_empty());
+
+        engine.add_line(";stdout.loop:end")?;
+        assert_eq!(
+            engine.take_outputs(),
+            vec![Output::Json(r#"{"c":1}"#.to_string()), Output::Stop]
+        );
+        assert_eq!(engine._get_json_object(), json!({}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_commands_errors() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";a:1")?;
+        engine.add_line(";b.c:1")?;
+        let e = engine.add_line(";!delete:x");
+        assert!(matches!(e, Err(Error::MissingKey(ref k)) if k == "x"));
+        let e = engine.add_line(";!pop:a");
+        assert!(matches!(e, Err(Error::NotAnArray(_))));
+        let e = engine.add_line(";!frobnicate");
+        assert!(matches!(e, Err(Error::UnknownCommand(_))));
+        let e = engine.add_line(";stdout.loop:frobnicate");
+        assert!(matches!(e, Err(Error::UnknownCommand(_))));
+        let e = engine.add_line(";!set:a@int=x");
+        assert!(matches!(e, Err(Error::InvalidValue(_))));
+
+        // a failed rename leaves the values as they were
+        let options = EngineOptions::new().with_how_to_leaf_in_dict(HowToLeafInDict::GenerateError);
+        let mut engine = Engine::new(options);
+        engine.add_line(";a:1")?;
+        engine.add_line(";b.c:1")?;
+        let e = engine.add_line(";!rename:a=b");
+        assert!(matches!(e, Err(Error::HowToLeafInDict)));
+        assert_eq!(engine._get_json_object(), json!({"a": 1, "b": {"c": 1}}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scopes() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new().with_multi_line_values(true));
+        engine.add_line(";!begin:solver")?;
+        engine.add_line(";name:cg")?;
+        engine.add_line(";!begin:iteration[]")?;
+        engine.add_line(";residual:1e-3")?;
+        engine.add_line(";!end:iteration[]")?;
+        engine.add_line(";!begin:iteration[]")?;
+        engine.add_line(";residual:1e-4")?;
+        engine.add_line(";r2:1")?;
+        engine.add_line(";!end:iteration[]")?;
+        engine.add_line(";!begin:iteration[-1]")?;
+        engine.add_line(";step:<<EOF")?;
+        engine.add_line("1")?;
+        engine.add_line("EOF")?;
+        engine.add_line(";!set:residual=2e-3")?;
+        engine.add_line(";!end")?;
+        engine.add_line(";!delete:name")?;
+        engine.add_line(";!end:solver")?;
+        engine.add_line(";a:1")?;
+        engine.finish()?;
+        let correct_json = json!({"solver": {"iteration": [
+            {"residual": 1e-3},
+            {"residual": 2e-3, "r2": 1, "step": "1"}
+        ]}, "a": 1});
+        assert_eq!(engine._get_json_object(), correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_scopes_unbalanced() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        let e = engine.add_line(";!end");
+        assert!(matches!(e, Err(Error::UnbalancedScope(_))));
+        engine.add_line(";!begin:a")?;
+        let e = engine.add_line(";!end:b");
+        assert!(matches!(e, Err(Error::UnbalancedScope(_))));
+        engine.add_line(";x:1")?;
+        let e = engine.finish();
+        assert!(matches!(e, Err(Error::UnbalancedScope(_))));
+        engine.add_line(";y:1")?;
+        assert_eq!(engine._get_json_object(), json!({"a": {"x": 1}, "y": 1}));
+        Ok(())
+    }
+
+    #[test]
+    fn test_scopes_element_gone() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_record_boundary(RecordBoundary::MarkerKey("s.it[0].end".to_string()));
+        let mut engine = Engine::new(options);
+        engine.add_line(";!begin:it[]")?;
+        engine.add_line(";x:1")?;
+        engine.add_line(";!flush")?;
+        engine.add_line(";y:2")?;
+        engine.add_line(";!clear")?;
+        engine.add_line(";!set:z=3")?;
+        engine.add_line(";!flush")?;
+        engine.add_line(";!end")?;
+
+        // nested in a scope whose element is gone
+        engine.add_line(";!begin:s")?;
+        engine.add_line(";!begin:it[]")?;
+        engine.add_line(";x:4")?;
+        engine.add_line(";end:1")?;
+        engine.add_line(";x:5")?;
+        engine.add_line(";!end")?;
+        engine.add_line(";!end")?;
+        engine.finish()?;
+        engine.flush();
+        let outputs: Vec<Output> = [
+            json!({"it": [{"x": 1}]}),
+            json!({"it": [{"z": 3}]}),
+            json!({"s": {"it": [{"x": 4}]}}),
+            json!({"s": {"it": [{"x": 5}]}}),
+        ]
+        .iter()
+        .map(|json| Output::Json(json.to_string()))
+        .collect();
+        assert_eq!(engine.take_outputs(), outputs);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_next() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";!next:it")?;
+        engine.add_line(";it.x:1")?;
+        engine.add_line(";it.y:2")?;
+        engine.add_line(";!next:it")?;
+        engine.add_line(";it.x:3")?;
+        engine.add_line(";it.x:4")?;
+        engine.add_line(";other.x:5")?;
+        let correct_json = json!({"it": [{"x": 1, "y": 2}, {"x": [3, 4]}], "other": {"x": 5}});
+        assert_eq!(engine._get_json_object(), correct_json);
+
+        engine.add_line(";a:1")?;
+        let e = engine.add_line(";!next:a");
+        assert!(matches!(e, Err(Error::NotAnArray(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_auto() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_group_key("s.it".to_string())
+            .with_meta_mode(MetaMode::Subtree);
+        let mut engine = Engine::new(options);
+        engine.add_line(";!begin:s")?;
+        for i in 0..2 {
+            engine.add_line(&format!(";it.x:{}", i))?;
+            engine.add_line(&format!(";it.pos.y:{}", i))?;
+        }
+        engine.add_line(";!end")?;
+        let mut json = engine._get_json_object();
+        strip_times(&mut json);
+        let correct_json = json!({
+            "s": {"it": [{"x": 0, "pos": {"y": 0}}, {"x": 1, "pos": {"y": 1}}]},
+            "_meta": {"s": {"it": [
+                {"x": {"line": 2}, "pos": {"y": {"line": 3}}},
+                {"x": {"line": 4}, "pos": {"y": {"line": 5}}}
+            ]}}
+        });
+        assert_eq!(json, correct_json);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_many_lines() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_group_key("it".to_string())
+            .with_pair_separator(";".to_string())
+            .with_meta_mode(MetaMode::Inline);
+        let mut engine = Engine::new(options);
+        let lines = 5000;
+        for i in 0..lines {
+            engine.add_line(&format!(";it.x:{};it.y:{}", i, i))?;
+        }
+        // the element added for the failed line is removed
+        assert!(engine.add_line(";it.x:1;it.y[0]:1").is_err());
+        let Some(Node::Array(it)) = engine.values().get("it") else {
+            panic!("it is not an array")
+        };
+        assert_eq!(it.len(), lines);
+        Ok(())
+    }
+
+    #[test]
+    fn test_group_invalid_key() {
+        let options = EngineOptions::new().with_group_key("it[x]".to_string());
+        let mut engine = Engine::new(options);
+        for _ in 0..2 {
+            assert!(matches!(engine.add_line(";a:1"), Err(Error::InvalidKey(_))));
+        }
+    }
+
+    #[test]
+    fn test_transpose() -> anyhow::Result<()> {
+        let mut engine = Engine::new(EngineOptions::new());
+        engine.add_line(";it.x:1")?;
+        engine.add_line(";it.y:2")?;
+        engine.add_line(";it.x:3")?;
+        engine.add_line(";!transpose:it")?;
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"it": [{"x": 1, "y": 2}, {"x": 3}]})
+        );
+        engine.add_line(";!transpose:it")?;
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"it": {"x": [1, 3], "y": [2, null]}})
+        );
+        engine.add_line(";!transpose:it")?;
+        assert_eq!(
+            engine._get_json_object(),
+            json!({"it": [{"x": 1, "y": 2}, {"x": 3, "y": null}]})
+        );
+        engine.add_line(";a:1")?;
+        let e = engine.add_line(";!transpose:a");
+        assert!(matches!(e, Err(Error::CannotTranspose(_))));
+
+        let options = EngineOptions::new().with_transpose_key("it".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";it.x:1")?;
+        engine.add_line(";it.x:2")?;
+        assert_eq!(engine.get_json(), r#"{"it":[{"x":1},{"x":2}]}"#);
+        Ok(())
+    }
+
+    #[test]
+    fn test_transpose_groups() -> anyhow::Result<()> {
+        let options = EngineOptions::new()
+            .with_group_key("it".to_string())
+            .with_transpose_key("it".to_string());
+        let mut engine = Engine::new(options);
+        engine.add_line(";it.x:1")?;
+        engine.add_line(";it.y:2")?;
+        engine.add_line(";it.x:3")?;
+        engine.add_line(";it.z:4")?;
+        engine.add_line(";it.x:5")?;
+        assert_eq!(
+            engine.get_json(),
+            r#"{"it":{"x":[1,3,5],"y":[2,null,null],"z":[null,4,null]}}"#
+        );
+        // transposed back, the missing fields are null
+        engine.add_line(";!transpose:it")?;
+        assert_eq!(
+            engine.get_json(),
+            r#"{"it":[{"x":1,"y":2,"z":null},{"x":3,"y":null,"z":4},{"x":5,"y":null,"z":null}]}"#
+        );
+        Ok(())
+    }
 }
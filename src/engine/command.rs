@@ -0,0 +1,212 @@
+use crate::engine::errors::{Error, Result};
+use crate::engine::key_path::{find_unquoted, KeySegment};
+use crate::jsonmodels::{MapNodes, Node};
+
+/// A control command, ie `;!delete:a.b`. The command name follows `!` and its
+/// argument comes after the key to value delimiter.
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Command {
+    /// `!clear`: forget the values
+    Clear,
+    /// `!flush`: write the json and start over
+    Flush,
+    /// `!snapshot`: write the json without starting over
+    Snapshot,
+    /// `!exit`: write the json and stop
+    Exit,
+    /// `!begin:a.b`: prepend `a.b` to the keys of the following lines, until
+    /// the matching `!end`. Scopes can be nested
+    Begin(String),
+    /// `!end` or `!end:a.b`: close the last scope, which must be `a.b` if given
+    End(Option<String>),
+    /// `!delete:a.b`: remove a key, or an element with `a.b[1]`
+    Delete(String),
+    /// `!set:a.b=1`: set a value, replacing the one already there if any. The
+    /// key can have a type annotation (`!set:a@str=1`)
+    Set { dotted_key: String, value: String },
+    /// `!push:a.b=1`: append a value to an array, same as `;a.b[]:1`
+    Push { dotted_key: String, value: String },
+    /// `!pop:a.b`: remove the last element of an array
+    Pop(String),
+    /// `!rename:a.b=c`: move a value to another key, merged with the value
+    /// already there if any
+    Rename { from: String, to: String },
+    /// `!emit:a.b`: write the json of a subtree and remove it
+    Emit(String),
+    /// `!next:it`: start a new element of the array of objects `it`, the
+    /// following `;it.x` lines setting the fields of this element
+    Next(String),
+    /// `!transpose:it`: switch between an array of objects and an object of
+    /// arrays, the fields missing in some objects becoming null
+    Transpose(String),
+}
+
+impl Command {
+    /// Parse a command from its name and argument, ie `delete` and `a.b`
+    pub fn parse(name: &str, argument: Option<&str>) -> Result<Self> {
+        let name = name.trim();
+        let argument = argument.map(str::trim).filter(|a| !a.is_empty());
+        let command = match (name, argument) {
+            ("clear", None) => Command::Clear,
+            ("flush", None) => Command::Flush,
+            ("snapshot", None) => Command::Snapshot,
+            ("exit", None) => Command::Exit,
+            ("begin", Some(dotted_key)) => Command::Begin(dotted_key.to_string()),
+            ("end", dotted_key) => Command::End(dotted_key.map(str::to_string)),
+            ("delete", Some(dotted_key)) => Command::Delete(dotted_key.to_string()),
+            ("pop", Some(dotted_key)) => Command::Pop(dotted_key.to_string()),
+            ("emit", Some(dotted_key)) => Command::Emit(dotted_key.to_string()),
+            ("next", Some(dotted_key)) => Command::Next(dotted_key.to_string()),
+            ("transpose", Some(dotted_key)) => Command::Transpose(dotted_key.to_string()),
+            ("set", Some(argument)) => {
+                let (dotted_key, value) = split_assignment(argument)?;
+                Command::Set { dotted_key, value }
+            }
+            ("push", Some(argument)) => {
+                let (dotted_key, value) = split_assignment(argument)?;
+                Command::Push { dotted_key, value }
+            }
+            ("rename", Some(argument)) => {
+                let (from, to) = split_assignment(argument)?;
+                Command::Rename {
+                    from,
+                    to: to.trim().to_string(),
+                }
+            }
+            (name, Some(argument)) => {
+                return Err(Error::UnknownCommand(format!("{}:{}", name, argument)))
+            }
+            (name, None) => return Err(Error::UnknownCommand(name.to_string())),
+        };
+        Ok(command)
+    }
+
+    /// Parse a command of the `;stdout.loop:flush` form, where `end` stops
+    pub fn parse_legacy(name: &str) -> Result<Self> {
+        match name.trim() {
+            "end" => Ok(Command::Exit),
+            name => Command::parse(name, None),
+        }
+    }
+}
+
+/// Split `a.b=1` at the first `=` which is neither escaped nor quoted
+fn split_assignment(argument: &str) -> Result<(String, String)> {
+    let i = find_unquoted(argument, "=")
+        .ok_or_else(|| Error::MissingDelimiter(argument.to_string()))?;
+    Ok((
+        argument[..i].trim().to_string(),
+        argument[i + 1..].to_string(),
+    ))
+}
+
+fn resolve_index(len: usize, i: i64) -> Option<usize> {
+    let i = if i < 0 { len as i64 + i } else { i };
+    (0..len as i64).contains(&i).then_some(i as usize)
+}
+
+/// The node at a key path, if any
+pub fn node_at_path<'a>(h: &'a mut MapNodes, segments: &[KeySegment]) -> Option<&'a mut Node> {
+    let (KeySegment::Key(k), rest) = segments.split_first()? else {
+        return None;
+    };
+    let mut node = h.nodes.get_mut(k)?;
+    for segment in rest {
+        node = match (segment, node) {
+            (KeySegment::Key(k), Node::Dict(m)) => m.nodes.get_mut(k)?,
+            (KeySegment::Index(i), Node::Array(a)) => {
+                let i = resolve_index(a.len(), *i)?;
+                &mut a[i]
+            }
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+/// Remove the node at a key path, keeping the order of the other keys
+pub fn remove_at_path(h: &mut MapNodes, segments: &[KeySegment]) -> Option<Node> {
+    let (last, parent) = segments.split_last()?;
+    if parent.is_empty() {
+        return match last {
+            KeySegment::Key(k) => h.nodes.shift_remove(k),
+            _ => None,
+        };
+    }
+    match (node_at_path(h, parent)?, last) {
+        (Node::Dict(m), KeySegment::Key(k)) => m.nodes.shift_remove(k),
+        (Node::Array(a), KeySegment::Index(i)) => {
+            let i = resolve_index(a.len(), *i)?;
+            Some(a.remove(i))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine::key_path::parse_key_path;
+    use serde_json::json;
+
+    fn segments(dotted_key: &str) -> Vec<KeySegment> {
+        parse_key_path(dotted_key, ".").unwrap()
+    }
+
+    #[test]
+    fn test_parse() -> Result<()> {
+        assert_eq!(Command::parse("flush", None)?, Command::Flush);
+        assert_eq!(Command::parse("end", Some(""))?, Command::End(None));
+        assert_eq!(Command::parse_legacy(" end ")?, Command::Exit);
+        assert_eq!(
+            Command::parse("begin", Some("a.b"))?,
+            Command::Begin("a.b".to_string())
+        );
+        assert_eq!(
+            Command::parse("delete", Some(" a.b "))?,
+            Command::Delete("a.b".to_string())
+        );
+        assert_eq!(
+            Command::parse("set", Some(r#"a."x=y"@int=1"#))?,
+            Command::Set {
+                dotted_key: r#"a."x=y"@int"#.to_string(),
+                value: "1".to_string()
+            }
+        );
+        assert_eq!(
+            Command::parse("rename", Some("a.b = c"))?,
+            Command::Rename {
+                from: "a.b".to_string(),
+                to: "c".to_string()
+            }
+        );
+        assert!(matches!(
+            Command::parse("set", Some("a")),
+            Err(Error::MissingDelimiter(_))
+        ));
+        assert!(matches!(
+            Command::parse("delete", None),
+            Err(Error::UnknownCommand(_))
+        ));
+        assert!(matches!(
+            Command::parse("frobnicate", None),
+            Err(Error::UnknownCommand(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_at_path() {
+        let mut h: MapNodes =
+            serde_json::from_value(json!({"a": {"b": 1, "c": [1, 2, 3]}, "d": 2})).unwrap();
+        assert!(remove_at_path(&mut h, &segments("a.b")).is_some());
+        assert!(remove_at_path(&mut h, &segments("a.c[-1]")).is_some());
+        assert!(remove_at_path(&mut h, &segments("a.c[5]")).is_none());
+        assert!(remove_at_path(&mut h, &segments("a.x")).is_none());
+        assert!(remove_at_path(&mut h, &segments("d.x")).is_none());
+        assert_eq!(h._json_object(), json!({"a": {"c": [1, 2]}, "d": 2}));
+        assert!(remove_at_path(&mut h, &segments("a")).is_some());
+        assert_eq!(h._json_object(), json!({"d": 2}));
+    }
+}
@@ -1,4 +1,9 @@
+use crate::engine::glob::KeyGlob;
+use crate::engine::units::{default_units, Unit};
+#[cfg(feature = "cli")]
 use clap::ValueEnum;
+use indexmap::IndexMap;
+use regex::Regex;
 
 /// Stageies to handle a dict in an array
 /// Example:
@@ -9,7 +14,9 @@ use clap::ValueEnum;
 /// ```
 ///
 /// How to add "a.b" : 4 ?
-#[derive(Default, Debug, ValueEnum, Clone)]
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
 pub enum HowToDictInArray {
     /// Will generate an error when adding the line
     #[default]
@@ -31,19 +38,330 @@ pub enum HowToDictInArray {
     MakeArrayAsDictValue,
 }
 
+/// Strategies to handle a leaf meeting a dict
+/// Example:
+/// ```json
+/// {
+///  "a" : {"b": 1}
+/// }
+/// ```
+///
+/// How to add "a" : 2 ?
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
+pub enum HowToLeafInDict {
+    /// Will store the leaf under the "value" key of the dict, ie the dict will be:
+    /// ```json
+    /// {
+    /// "a" : {"b": 1, "value": 2}
+    /// }
+    /// ```
+    /// The same is done when a dict is added to an existing leaf.
+    #[default]
+    MakeLeafAsDictValue,
+    /// Will generate an error when adding the line
+    GenerateError,
+}
+
+/// Strategies to handle an array meeting a leaf or another array
+/// Example:
+/// ```json
+/// {
+///  "a" : [1,2]
+/// }
+/// ```
+///
+/// How to add `"a" : [3,4]` ?
+#[derive(Default, Debug, Clone)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
+pub enum HowToArrayInArray {
+    /// Will concatenate both arrays, ie the array will be:
+    /// ```json
+    /// {
+    /// "a" : [1,2,3,4]
+    /// }
+    /// ```
+    #[default]
+    Concatenate,
+    /// Will add the array as a single element, ie the array will be:
+    /// ```json
+    /// {
+    /// "a" : [1,2,[3,4]]
+    /// }
+    /// ```
+    Nest,
+    /// Will generate an error when adding the line
+    GenerateError,
+}
+
+/// Strategies to handle a leaf printed again under the same key
+/// Example:
+/// ```json
+/// {
+///  "a" : 1
+/// }
+/// ```
+///
+/// How to add "a" : 2 ?
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
+pub enum HowToRepeatedLeaf {
+    /// Will convert the value to an array, ie the value will be:
+    /// ```json
+    /// {
+    /// "a" : [1,2]
+    /// }
+    /// ```
+    #[default]
+    AppendArray,
+    /// Will keep the last value, ie `{"a": 2}`
+    LastWins,
+    /// Will keep the first value, ie `{"a": 1}`
+    FirstWins,
+    /// Will generate an error when adding the line
+    #[cfg_attr(feature = "cli", value(alias = "error"))]
+    GenerateError,
+    /// Like `AppendArray`, but a key printed only once is also an array, ie
+    /// `"a" : 1` alone gives `{"a": [1]}`
+    AlwaysArray,
+}
+
+/// Policy applied to the repeated leaves whose dotted key matches `pattern`
+#[derive(Debug, Clone)]
+pub struct RepeatedLeafOverride {
+    pattern: KeyGlob,
+    how: HowToRepeatedLeaf,
+}
+
+impl RepeatedLeafOverride {
+    pub fn new(pattern: &str, how: HowToRepeatedLeaf) -> Self {
+        RepeatedLeafOverride {
+            pattern: KeyGlob::new(pattern),
+            how,
+        }
+    }
+}
+
+/// Order of the keys in the generated json
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
+pub enum KeyOrder {
+    /// Keys are written in the order they were first printed
+    #[default]
+    Insertion,
+    /// Keys are sorted alphabetically
+    Sorted,
+}
+
+/// Where the line prefix is looked for
+/// Example, with lines written by a logger:
+/// ```text
+/// 2026-10-01 12:00:00 INFO ;a.b:1
+/// ```
+#[derive(Default, Debug, Clone)]
+#[non_exhaustive]
+pub enum PrefixLocation {
+    /// The line must start with the prefix
+    #[default]
+    Start,
+    /// The first occurrence of the prefix in the line is used
+    Anywhere,
+    /// The prefix must directly follow the first match of the regex, ie
+    /// `^\S+ \S+ INFO ` for the example above
+    AfterRegex(Regex),
+}
+
+/// How to read a number followed by a unit, ie `;latency:12ms`
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
+pub enum UnitMode {
+    /// The value is a string
+    #[default]
+    Off,
+    /// The number and the unit are kept apart:
+    /// ```json
+    /// {"latency": {"value": 12, "unit": "ms"}}
+    /// ```
+    Object,
+    /// The number is converted to the SI unit:
+    /// ```json
+    /// {"latency": 0.012}
+    /// ```
+    Normalize,
+}
+
+/// How to write timestamps and durations, ie `;t:2024-03-01 12:30:00+02:00`
+/// and `;d:1h2m3s`
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
+pub enum TimeFormat {
+    /// Values are written as they were printed
+    #[default]
+    Keep,
+    /// RFC 3339 timestamps in UTC and ISO-8601 durations:
+    /// ```json
+    /// {"t": "2024-03-01T10:30:00Z", "d": "PT1H2M3S"}
+    /// ```
+    Canonical,
+    /// Seconds since the Unix epoch, seconds for durations:
+    /// ```json
+    /// {"t": 1709289000, "d": 3723}
+    /// ```
+    Epoch,
+}
+
+/// What ends the current record, which is then written as a line of json
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum RecordBoundary {
+    /// A key already set in the record is printed again, ie `id` in
+    /// `;id:1\n;a:1\n;id:2`
+    RepeatedKey(String),
+    /// An empty line
+    BlankLine,
+    /// A line holding this key, whose value is ignored, ie `;end:1`
+    MarkerKey(String),
+    /// A line without prefix
+    UnmatchedLine,
+}
+
+/// Whether to record the arrival time and the line number of the leaves, ie
+/// for `;a:1` read on line 12
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(feature = "cli", derive(ValueEnum))]
+#[non_exhaustive]
+pub enum MetaMode {
+    /// Only the values are written
+    #[default]
+    Off,
+    /// The leaves are replaced by an object:
+    /// ```json
+    /// {"a": {"v": 1, "t": 1709289000.25, "line": 12}}
+    /// ```
+    Inline,
+    /// The meta data are written in a `_meta` subtree of the same shape:
+    /// ```json
+    /// {"a": 1, "_meta": {"a": {"t": 1709289000.25, "line": 12}}}
+    /// ```
+    Subtree,
+}
+
 #[derive(Debug)]
 pub struct EngineOptions {
-    pub verbosity: u8,
-    pub how_to_dict_in_array: HowToDictInArray,
-    pub key_to_value_delimiter: String
+    pub(crate) verbosity: u8,
+    pub(crate) how_to_dict_in_array: HowToDictInArray,
+    pub(crate) how_to_leaf_in_dict: HowToLeafInDict,
+    pub(crate) how_to_array_in_array: HowToArrayInArray,
+    pub(crate) how_to_repeated_leaf: HowToRepeatedLeaf,
+    /// Checked in order, the first matching pattern wins over `how_to_repeated_leaf`
+    pub(crate) repeated_leaf_overrides: Vec<RepeatedLeafOverride>,
+    pub(crate) key_order: KeyOrder,
+    /// Marks the lines holding a key and a value, ie `;` in `;a.b:1`
+    pub(crate) line_prefix: String,
+    pub(crate) prefix_location: PrefixLocation,
+    /// If set, the text found before the prefix is stored under this key. When
+    /// the prefix follows a regex having a capture group, the first group is
+    /// stored instead.
+    pub(crate) leading_text_key: Option<String>,
+    /// Separates the key from the value, ie `:` in `;a.b:1`
+    pub(crate) key_to_value_delimiter: String,
+    /// If set, a line can hold several pairs separated by this string, ie `;`
+    /// in `;a:1;b:2`. The pairs of a line are all applied or none is.
+    pub(crate) pair_separator: Option<String>,
+    /// A value `<<TERMINATOR` takes the following lines, up to a line holding
+    /// only the terminator. Off by default, as such a value printed by chance
+    /// would take all the lines up to the end of the input.
+    pub(crate) multi_line_values: bool,
+    /// Separates the keys of a dotted key, ie `.` in `a.b.c`
+    pub(crate) path_separator: String,
+    /// Convert the values to numbers, booleans or null. When false, values are
+    /// strings unless their key has a type annotation.
+    pub(crate) infer_types: bool,
+    /// Values read as `true`
+    pub(crate) true_words: Vec<String>,
+    /// Values read as `false`
+    pub(crate) false_words: Vec<String>,
+    /// Values read as `null`
+    pub(crate) null_words: Vec<String>,
+    /// Read `nan`, `inf` and `-inf` as `null`, json having no such numbers.
+    /// They are kept as strings otherwise.
+    pub(crate) non_finite_as_null: bool,
+    /// Read the `0x1F`, `0o17` and `0b1010` integer literals
+    pub(crate) radix_literals: bool,
+    /// Allow underscores between the digits of a number, ie `1_000_000`
+    pub(crate) digit_separators: bool,
+    /// Read integers above `i64::MAX` as unsigned 64 bits integers
+    pub(crate) unsigned_integers: bool,
+    /// Keep integers out of the 64 bits range exact, instead of reading them as floats
+    pub(crate) big_integers: bool,
+    pub(crate) unit_mode: UnitMode,
+    /// Units recognized after a number, by symbol
+    pub(crate) units: IndexMap<String, Unit>,
+    /// Timestamps and durations are recognized unless the format is
+    /// [TimeFormat::Keep]. Annotated values (`@time`, `@epoch`, `@epoch_ms`,
+    /// `@duration`) are always read.
+    pub(crate) time_format: TimeFormat,
+    /// The arrival times are written with the time format, as epoch seconds
+    /// unless it is [TimeFormat::Canonical]
+    pub(crate) meta_mode: MetaMode,
+    /// If not empty, a json object is written for each record
+    pub(crate) record_boundaries: Vec<RecordBoundary>,
+    /// A snapshot only holds the keys added or changed since the previous one,
+    /// the keys removed being set to null
+    pub(crate) snapshot_changes_only: bool,
+    /// Keys holding an array of objects, ie `it` for `;it.x:1` and `;it.y:2`,
+    /// a new object starting when one of its fields is printed again
+    pub(crate) group_keys: Vec<String>,
+    /// Keys switched between an array of objects and an object of arrays in the output
+    pub(crate) transpose_keys: Vec<String>,
+}
+
+impl Default for EngineOptions {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EngineOptions {
     pub fn new() -> Self {
         Self {
             verbosity: 0,
+            line_prefix: ";".to_string(),
+            prefix_location: PrefixLocation::default(),
+            leading_text_key: None,
             key_to_value_delimiter: ":".to_string(),
-            how_to_dict_in_array: HowToDictInArray::default()
+            pair_separator: None,
+            multi_line_values: false,
+            path_separator: ".".to_string(),
+            infer_types: true,
+            true_words: vec!["true".to_string()],
+            false_words: vec!["false".to_string()],
+            null_words: vec!["null".to_string()],
+            non_finite_as_null: false,
+            radix_literals: false,
+            digit_separators: false,
+            unsigned_integers: false,
+            big_integers: false,
+            unit_mode: UnitMode::default(),
+            units: default_units(),
+            time_format: TimeFormat::default(),
+            meta_mode: MetaMode::default(),
+            record_boundaries: Vec::new(),
+            snapshot_changes_only: false,
+            group_keys: Vec::new(),
+            transpose_keys: Vec::new(),
+            how_to_dict_in_array: HowToDictInArray::default(),
+            how_to_leaf_in_dict: HowToLeafInDict::default(),
+            how_to_array_in_array: HowToArrayInArray::default(),
+            how_to_repeated_leaf: HowToRepeatedLeaf::default(),
+            repeated_leaf_overrides: Vec::new(),
+            key_order: KeyOrder::default(),
         }
     }
 
@@ -52,15 +370,163 @@ impl EngineOptions {
         self
     }
 
-
-
     pub fn with_how_to_dict_in_array(mut self, h: HowToDictInArray) -> Self {
         self.how_to_dict_in_array = h;
         self
     }
 
-    pub fn with_key_delimiter(mut self, k: String) -> Self {
+    pub fn with_how_to_leaf_in_dict(mut self, h: HowToLeafInDict) -> Self {
+        self.how_to_leaf_in_dict = h;
+        self
+    }
+
+    pub fn with_how_to_array_in_array(mut self, h: HowToArrayInArray) -> Self {
+        self.how_to_array_in_array = h;
+        self
+    }
+
+    pub fn with_how_to_repeated_leaf(mut self, h: HowToRepeatedLeaf) -> Self {
+        self.how_to_repeated_leaf = h;
+        self
+    }
+
+    pub fn with_repeated_leaf_override(mut self, o: RepeatedLeafOverride) -> Self {
+        self.repeated_leaf_overrides.push(o);
+        self
+    }
+
+    /// Policy for repeated leaves under the dotted key `path`
+    pub(crate) fn how_to_repeated_leaf_for(&self, path: &[String]) -> HowToRepeatedLeaf {
+        self.repeated_leaf_overrides
+            .iter()
+            .find(|o| o.pattern.matches(path, &self.path_separator))
+            .map(|o| o.how)
+            .unwrap_or(self.how_to_repeated_leaf)
+    }
+
+    pub fn with_key_order(mut self, key_order: KeyOrder) -> Self {
+        self.key_order = key_order;
+        self
+    }
+
+    pub fn with_line_prefix(mut self, p: String) -> Self {
+        self.line_prefix = p;
+        self
+    }
+
+    pub fn with_prefix_location(mut self, l: PrefixLocation) -> Self {
+        self.prefix_location = l;
+        self
+    }
+
+    pub fn with_leading_text_key(mut self, k: String) -> Self {
+        self.leading_text_key = Some(k);
+        self
+    }
+
+    pub fn with_key_to_value_delimiter(mut self, k: String) -> Self {
         self.key_to_value_delimiter = k;
         self
     }
+
+    pub fn with_pair_separator(mut self, s: String) -> Self {
+        self.pair_separator = Some(s);
+        self
+    }
+
+    pub fn with_multi_line_values(mut self, b: bool) -> Self {
+        self.multi_line_values = b;
+        self
+    }
+
+    pub fn with_path_separator(mut self, s: String) -> Self {
+        self.path_separator = s;
+        self
+    }
+
+    pub fn with_infer_types(mut self, b: bool) -> Self {
+        self.infer_types = b;
+        self
+    }
+
+    pub fn with_true_word(mut self, w: String) -> Self {
+        self.true_words.push(w);
+        self
+    }
+
+    pub fn with_false_word(mut self, w: String) -> Self {
+        self.false_words.push(w);
+        self
+    }
+
+    pub fn with_null_word(mut self, w: String) -> Self {
+        self.null_words.push(w);
+        self
+    }
+
+    pub fn with_non_finite_as_null(mut self, b: bool) -> Self {
+        self.non_finite_as_null = b;
+        self
+    }
+
+    pub fn with_radix_literals(mut self, b: bool) -> Self {
+        self.radix_literals = b;
+        self
+    }
+
+    pub fn with_digit_separators(mut self, b: bool) -> Self {
+        self.digit_separators = b;
+        self
+    }
+
+    pub fn with_unsigned_integers(mut self, b: bool) -> Self {
+        self.unsigned_integers = b;
+        self
+    }
+
+    pub fn with_big_integers(mut self, b: bool) -> Self {
+        self.big_integers = b;
+        self
+    }
+
+    pub fn with_unit_mode(mut self, unit_mode: UnitMode) -> Self {
+        self.unit_mode = unit_mode;
+        self
+    }
+
+    pub fn with_time_format(mut self, time_format: TimeFormat) -> Self {
+        self.time_format = time_format;
+        self
+    }
+
+    pub fn with_meta_mode(mut self, meta_mode: MetaMode) -> Self {
+        self.meta_mode = meta_mode;
+        self
+    }
+
+    pub fn with_record_boundary(mut self, boundary: RecordBoundary) -> Self {
+        self.record_boundaries.push(boundary);
+        self
+    }
+
+    pub fn with_snapshot_changes_only(mut self, b: bool) -> Self {
+        self.snapshot_changes_only = b;
+        self
+    }
+
+    pub fn with_group_key(mut self, dotted_key: String) -> Self {
+        self.group_keys.push(dotted_key);
+        self
+    }
+
+    pub fn with_transpose_key(mut self, dotted_key: String) -> Self {
+        self.transpose_keys.push(dotted_key);
+        self
+    }
+
+    /// Add a unit, or replace a known one, converted to SI by `factor`
+    pub fn with_unit(mut self, symbol: String, factor: f64) -> Self {
+        self.units.insert(symbol, Unit::new(factor));
+        self
+    }
 }
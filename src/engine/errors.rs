@@ -2,14 +2,68 @@ use std::fmt::{Debug, Display, Formatter};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum Error {
     HowToDictInArray,
+    HowToLeafInDict,
+    HowToArrayInArray,
+    /// A leaf was printed again under a key using [HowToRepeatedLeaf::GenerateError]
+    ///
+    /// [HowToRepeatedLeaf::GenerateError]: crate::engine::engine_options::HowToRepeatedLeaf::GenerateError
+    RepeatedLeaf(String),
+    /// The key of a line can't be parsed
+    InvalidKey(String),
+    /// An array index in a key doesn't address an existing element
+    IndexOutOfRange(String),
+    /// An array index is used on a key which doesn't hold an array
+    NotAnArray(String),
+    /// A pair of a multi-pair line has no key to value delimiter
+    MissingDelimiter(String),
+    /// A value doesn't match the type annotation of its key
+    InvalidValue(String),
+    /// The input ended inside a multi-line value
+    UnterminatedBlock(String),
+    /// A control command can't be parsed
+    UnknownCommand(String),
+    /// A control command addresses a key which isn't set
+    MissingKey(String),
+    /// A `;!end` doesn't match a `;!begin`
+    UnbalancedScope(String),
+    /// A key holds neither an array of objects nor an object
+    CannotTranspose(String),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Error::RepeatedLeaf(key) => write!(f, "RepeatedLeaf: key {} is already set", key),
+            Error::InvalidKey(reason) => write!(f, "InvalidKey: {}", reason),
+            Error::IndexOutOfRange(key) => {
+                write!(f, "IndexOutOfRange: no such element in array {}", key)
+            }
+            Error::NotAnArray(key) => write!(f, "NotAnArray: key {} doesn't hold an array", key),
+            Error::MissingDelimiter(pair) => {
+                write!(f, "MissingDelimiter: no delimiter in '{}'", pair)
+            }
+            Error::InvalidValue(reason) => write!(f, "InvalidValue: {}", reason),
+            Error::UnterminatedBlock(key) => {
+                write!(
+                    f,
+                    "UnterminatedBlock: no terminator for the value of {}",
+                    key
+                )
+            }
+            Error::UnknownCommand(command) => write!(f, "UnknownCommand: {}", command),
+            Error::MissingKey(key) => write!(f, "MissingKey: key {} is not set", key),
+            Error::UnbalancedScope(reason) => write!(f, "UnbalancedScope: {}", reason),
+            Error::CannotTranspose(key) => write!(
+                f,
+                "CannotTranspose: key {} holds neither an array of objects nor an object",
+                key
+            ),
+            _ => write!(f, "{:?}", self),
+        }
     }
 }
 
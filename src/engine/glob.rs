@@ -0,0 +1,122 @@
+/// Glob pattern matched against the segments of a dotted key path.
+///
+/// Segments are separated by the path separator (`.` by default). Inside a
+/// segment, `*` matches any sequence of characters and `?` matches a single
+/// character. A segment made of `**` matches any number of segments, including
+/// none.
+///
+/// ```text
+/// a.*      matches a.b, a.c           but not a, a.b.c
+/// a.**     matches a, a.b, a.b.c
+/// **.id    matches id, a.id, a.b.id
+/// ```
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyGlob {
+    pattern: String,
+}
+
+impl KeyGlob {
+    pub fn new(pattern: &str) -> Self {
+        KeyGlob {
+            pattern: pattern.to_string(),
+        }
+    }
+
+    pub fn matches(&self, path: &[String], separator: &str) -> bool {
+        let segments: Vec<String> = if separator.is_empty() {
+            vec![self.pattern.clone()]
+        } else {
+            self.pattern
+                .split(separator)
+                .map(|s| s.to_string())
+                .collect()
+        };
+        match_segments(&segments, path)
+    }
+}
+
+fn match_segments(pattern: &[String], path: &[String]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((p, rest)) if p == "**" => {
+            (0..=path.len()).any(|skipped| match_segments(rest, &path[skipped..]))
+        }
+        Some((p, rest)) => match path.split_first() {
+            Some((s, path_rest)) => match_segment(p, s) && match_segments(rest, path_rest),
+            None => false,
+        },
+    }
+}
+
+fn match_segment(pattern: &str, segment: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let segment: Vec<char> = segment.chars().collect();
+    match_chars(&pattern, &segment)
+}
+
+fn match_chars(pattern: &[char], segment: &[char]) -> bool {
+    match pattern.split_first() {
+        None => segment.is_empty(),
+        Some(('*', rest)) => {
+            (0..=segment.len()).any(|skipped| match_chars(rest, &segment[skipped..]))
+        }
+        Some(('?', rest)) => !segment.is_empty() && match_chars(rest, &segment[1..]),
+        Some((c, rest)) => segment.first() == Some(c) && match_chars(rest, &segment[1..]),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn path(s: &str) -> Vec<String> {
+        s.split('.').map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_exact() {
+        let g = KeyGlob::new("a.b");
+        assert!(g.matches(&path("a.b"), "."));
+        assert!(!g.matches(&path("a"), "."));
+        assert!(!g.matches(&path("a.b.c"), "."));
+    }
+
+    #[test]
+    fn test_star() {
+        let g = KeyGlob::new("a.*");
+        assert!(g.matches(&path("a.b"), "."));
+        assert!(!g.matches(&path("a"), "."));
+        assert!(!g.matches(&path("a.b.c"), "."));
+
+        let g = KeyGlob::new("a.temp_*");
+        assert!(g.matches(&path("a.temp_1"), "."));
+        assert!(g.matches(&path("a.temp_"), "."));
+        assert!(!g.matches(&path("a.hum_1"), "."));
+
+        let g = KeyGlob::new("a.t?");
+        assert!(g.matches(&path("a.t1"), "."));
+        assert!(!g.matches(&path("a.t12"), "."));
+    }
+
+    #[test]
+    fn test_double_star() {
+        let g = KeyGlob::new("**.id");
+        assert!(g.matches(&path("id"), "."));
+        assert!(g.matches(&path("a.id"), "."));
+        assert!(g.matches(&path("a.b.id"), "."));
+        assert!(!g.matches(&path("a.b"), "."));
+
+        let g = KeyGlob::new("a.**");
+        assert!(g.matches(&path("a"), "."));
+        assert!(g.matches(&path("a.b.c"), "."));
+        assert!(!g.matches(&path("b.c"), "."));
+    }
+
+    #[test]
+    fn test_separator() {
+        let g = KeyGlob::new("a/*");
+        let path = vec!["a".to_string(), "10.0.0.1".to_string()];
+        assert!(g.matches(&path, "/"));
+        assert!(!g.matches(&path, "."));
+    }
+}
@@ -0,0 +1,332 @@
+use crate::engine::errors::{Error, Result};
+use crate::engine::value_parser::ValueType;
+use std::str::FromStr;
+
+/// One element of a dotted key
+#[derive(Debug, Clone, PartialEq)]
+pub enum KeySegment {
+    /// `name`: a key of a dict
+    Key(String),
+    /// `[2]`, `[-1]`: an existing element of an array, negative indexes count from the end
+    Index(i64),
+    /// `[]`: a new element pushed at the end of an array
+    Append,
+}
+
+/// Split a dotted key like `points[].x` or `a.b[-1].c` into its segments.
+///
+/// Keys are separated by `separator` and each key can be followed by any number
+/// of array markers: `[n]` for an existing element and `[]` for a new one.
+/// A backslash escapes the next character (`lib\.so`) and double quotes
+/// protect a whole part of a key (`"10.0.0.1".latency`).
+pub fn parse_key_path(dotted_key: &str, separator: &str) -> Result<Vec<KeySegment>> {
+    let mut segments = Vec::new();
+    let mut name = String::new();
+    // true once the array markers following a key are being read
+    let mut in_indexes = false;
+    let mut rest = dotted_key;
+
+    while !rest.is_empty() {
+        if !separator.is_empty() && rest.starts_with(separator) {
+            if !in_indexes {
+                segments.push(KeySegment::Key(std::mem::take(&mut name)));
+            }
+            in_indexes = false;
+            rest = &rest[separator.len()..];
+            continue;
+        }
+
+        let c = rest.chars().next().unwrap();
+        if in_indexes && c != '[' {
+            return Err(invalid_key(dotted_key, "expected a separator after ]"));
+        }
+        match c {
+            '\\' => {
+                let escaped = rest[1..]
+                    .chars()
+                    .next()
+                    .ok_or_else(|| invalid_key(dotted_key, "nothing to escape after \\"))?;
+                name.push(escaped);
+                rest = &rest[1 + escaped.len_utf8()..];
+            }
+            '"' => {
+                let (quoted, r) = read_quoted(&rest[1..])
+                    .ok_or_else(|| invalid_key(dotted_key, "missing closing quote"))?;
+                name.push_str(&quoted);
+                rest = r;
+            }
+            '[' => {
+                if !in_indexes {
+                    segments.push(KeySegment::Key(std::mem::take(&mut name)));
+                    in_indexes = true;
+                }
+                let (index, r) = rest[1..]
+                    .split_once(']')
+                    .ok_or_else(|| invalid_key(dotted_key, "missing closing ]"))?;
+                segments.push(parse_index(dotted_key, index)?);
+                rest = r;
+            }
+            c => {
+                name.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+    }
+    if !in_indexes {
+        segments.push(KeySegment::Key(name));
+    }
+    Ok(segments)
+}
+
+/// Split the type annotation from a key, ie `id@str` gives `("id", Some("str"))`.
+/// The annotation follows the last `@` which is neither escaped nor quoted, and
+/// must name a known type: `user@host` is a key without annotation.
+pub fn split_type_annotation(dotted_key: &str) -> (&str, Option<&str>) {
+    let mut quoted = false;
+    let mut at = None;
+    let mut chars = dotted_key.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            '@' if !quoted => at = Some(i),
+            _ => {}
+        }
+    }
+    match at {
+        Some(i) if ValueType::from_str(&dotted_key[i + 1..]).is_ok() => {
+            (&dotted_key[..i], Some(&dotted_key[i + 1..]))
+        }
+        _ => (dotted_key, None),
+    }
+}
+
+/// Find the first occurrence of `pattern` in `s` which is neither escaped by a
+/// backslash nor inside double quotes.
+pub fn find_unquoted(s: &str, pattern: &str) -> Option<usize> {
+    if pattern.is_empty() {
+        return None;
+    }
+    let mut quoted = false;
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if !quoted && s[i..].starts_with(pattern) {
+            return Some(i);
+        }
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Check that the double quotes of a pair are closed, a quote left open hiding
+/// the rest of the pair from [find_unquoted]
+pub fn check_quotes(pair: &str) -> Result<()> {
+    let mut quoted = false;
+    let mut chars = pair.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            _ => {}
+        }
+    }
+    if quoted {
+        return Err(invalid_key(pair, "missing closing quote"));
+    }
+    Ok(())
+}
+
+/// Read a quoted string, the opening quote being already consumed.
+/// Returns the unescaped content and what follows the closing quote.
+fn read_quoted(s: &str) -> Option<(String, &str)> {
+    let mut content = String::new();
+    let mut chars = s.char_indices();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((content, &s[i + 1..])),
+            '\\' => content.push(chars.next()?.1),
+            c => content.push(c),
+        }
+    }
+    None
+}
+
+fn parse_index(dotted_key: &str, index: &str) -> Result<KeySegment> {
+    if index.is_empty() {
+        return Ok(KeySegment::Append);
+    }
+    index
+        .parse::<i64>()
+        .map(KeySegment::Index)
+        .map_err(|_| invalid_key(dotted_key, &format!("'{}' is not an array index", index)))
+}
+
+fn invalid_key(dotted_key: &str, reason: &str) -> Error {
+    Error::InvalidKey(format!("{}: {}", dotted_key, reason))
+}
+
+/// True if the segments only address dicts, without any array marker
+pub fn is_plain_path(segments: &[KeySegment]) -> bool {
+    segments.iter().all(|s| matches!(s, KeySegment::Key(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use KeySegment::*;
+
+    fn key(s: &str) -> KeySegment {
+        Key(s.to_string())
+    }
+
+    fn parse_key_path_dotted(dotted_key: &str) -> Result<Vec<KeySegment>> {
+        parse_key_path(dotted_key, ".")
+    }
+
+    #[test]
+    fn test_plain() -> anyhow::Result<()> {
+        assert_eq!(parse_key_path_dotted("a")?, vec![key("a")]);
+        assert_eq!(
+            parse_key_path_dotted("a.b.c")?,
+            vec![key("a"), key("b"), key("c")]
+        );
+        assert!(is_plain_path(&parse_key_path_dotted("a.b.c")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_indexes() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_key_path_dotted("points[].x")?,
+            vec![key("points"), Append, key("x")]
+        );
+        assert_eq!(
+            parse_key_path_dotted("a[2].b[-1]")?,
+            vec![key("a"), Index(2), key("b"), Index(-1)]
+        );
+        assert_eq!(
+            parse_key_path_dotted("m[0][]")?,
+            vec![key("m"), Index(0), Append]
+        );
+        assert!(!is_plain_path(&parse_key_path_dotted("a[0]")?));
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            parse_key_path_dotted("a[x]"),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            parse_key_path_dotted("a[0"),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            parse_key_path_dotted("a[0]b"),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            parse_key_path_dotted("a\\"),
+            Err(Error::InvalidKey(_))
+        ));
+        assert!(matches!(
+            parse_key_path_dotted("\"a.b"),
+            Err(Error::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_escapes() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_key_path_dotted(r"libs.lib\.so\.1")?,
+            vec![key("libs"), key("lib.so.1")]
+        );
+        assert_eq!(parse_key_path_dotted(r"a\[0\]")?, vec![key("a[0]")]);
+        assert_eq!(parse_key_path_dotted(r"a\\b")?, vec![key(r"a\b")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quotes() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_key_path_dotted(r#""10.0.0.1".latency"#)?,
+            vec![key("10.0.0.1"), key("latency")]
+        );
+        assert_eq!(
+            parse_key_path_dotted(r#"hosts."a.b"[0]"#)?,
+            vec![key("hosts"), key("a.b"), Index(0)]
+        );
+        assert_eq!(
+            parse_key_path_dotted(r#"v"1.2"_rc.x"#)?,
+            vec![key("v1.2_rc"), key("x")]
+        );
+        assert_eq!(
+            parse_key_path_dotted(r#""say \"hi\"""#)?,
+            vec![key(r#"say "hi""#)]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_annotation() {
+        assert_eq!(split_type_annotation("a.b"), ("a.b", None));
+        assert_eq!(split_type_annotation("a.b@str"), ("a.b", Some("str")));
+        assert_eq!(split_type_annotation("a[]@int"), ("a[]", Some("int")));
+        assert_eq!(split_type_annotation("a[]@"), ("a[]@", None));
+        assert_eq!(split_type_annotation("user@host"), ("user@host", None));
+        assert_eq!(
+            split_type_annotation("user@host@str"),
+            ("user@host", Some("str"))
+        );
+        assert_eq!(
+            split_type_annotation(r"mail\@host@str"),
+            (r"mail\@host", Some("str"))
+        );
+        assert_eq!(split_type_annotation(r#""a@b".c"#), (r#""a@b".c"#, None));
+    }
+
+    #[test]
+    fn test_find_unquoted() {
+        assert_eq!(find_unquoted("a:1", ":"), Some(1));
+        assert_eq!(find_unquoted(r#""a:b".c:1"#, ":"), Some(7));
+        assert_eq!(find_unquoted(r"a\:b:1", ":"), Some(4));
+        assert_eq!(find_unquoted(r#"m:"x;y";n:1"#, ";"), Some(7));
+        assert_eq!(find_unquoted(r#"m:"x\";y""#, ";"), None);
+        assert_eq!(find_unquoted("a::b", "::"), Some(1));
+        assert_eq!(find_unquoted("ab", ""), None);
+    }
+
+    #[test]
+    fn test_check_quotes() {
+        assert!(check_quotes(r#""a:b".c:1"#).is_ok());
+        assert!(check_quotes(r#"a\":1"#).is_ok());
+        assert!(matches!(
+            check_quotes(r#"5" tv:1"#),
+            Err(Error::InvalidKey(_))
+        ));
+    }
+
+    #[test]
+    fn test_separator() -> anyhow::Result<()> {
+        assert_eq!(
+            parse_key_path("hosts/10.0.0.1/latency", "/")?,
+            vec![key("hosts"), key("10.0.0.1"), key("latency")]
+        );
+        assert_eq!(
+            parse_key_path("a::b[]::c", "::")?,
+            vec![key("a"), key("b"), Append, key("c")]
+        );
+        Ok(())
+    }
+}
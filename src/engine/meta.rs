@@ -0,0 +1,210 @@
+use crate::engine::engine_options::{MetaMode, TimeFormat};
+use crate::engine::time::Timestamp;
+use crate::jsonmodels::{MapNodes, Node, TypedValue};
+use std::collections::HashMap;
+
+const VALUE_KEY: &str = "v";
+const TIME_KEY: &str = "t";
+const LINE_KEY: &str = "line";
+/// Key of the meta subtree, at the root of the json
+pub const META_SUBTREE_KEY: &str = "_meta";
+
+/// Arrival time and line number of the leaves.
+///
+/// The meta data are kept in a tree having the shape of the values: each leaf
+/// inserted in the values is mirrored by its line number, inserted at the same
+/// path with the same options, so that both trees are merged alike.
+#[derive(Debug, Default, Clone)]
+pub struct Meta {
+    pub lines: MapNodes,
+    /// Arrival time of the lines having inserted leaves
+    pub times: HashMap<i64, Timestamp>,
+}
+
+impl Meta {
+    pub fn clear(&mut self) {
+        self.lines.nodes.clear();
+        self.times.clear();
+    }
+
+    /// The node to insert in the meta tree for `value`, read at `line`
+    pub fn mirror(&mut self, value: &Node, line: i64) -> Node {
+        self.times.entry(line).or_insert_with(Timestamp::now);
+        mirror_leaves(value, line)
+    }
+
+    /// The values with their meta data, according to the mode
+    pub fn render(&self, values: &MapNodes, mode: MetaMode, format: TimeFormat) -> MapNodes {
+        match mode {
+            MetaMode::Off => values.clone(),
+            MetaMode::Inline => self.inline_map(values, &self.lines, format),
+            MetaMode::Subtree => {
+                let mut rendered = values.clone();
+                if !self.lines.nodes.is_empty() {
+                    let subtree = self.subtree(&Node::Dict(self.lines.clone()), format);
+                    rendered.nodes.insert(META_SUBTREE_KEY.to_string(), subtree);
+                }
+                rendered
+            }
+        }
+    }
+
+    /// A node removed from the values with its meta data, written as [Meta::render]
+    /// writes the values. In the subtree mode, only an object has a meta subtree.
+    pub fn render_node(
+        &self,
+        value: Node,
+        lines: Option<&Node>,
+        mode: MetaMode,
+        format: TimeFormat,
+    ) -> Node {
+        match (mode, lines, value) {
+            (MetaMode::Inline, Some(lines), value) => self.inline(&value, lines, format),
+            (MetaMode::Subtree, Some(lines @ Node::Dict(m)), Node::Dict(mut values))
+                if !m.nodes.is_empty() =>
+            {
+                let subtree = self.subtree(lines, format);
+                values.nodes.insert(META_SUBTREE_KEY.to_string(), subtree);
+                Node::Dict(values)
+            }
+            (_, _, value) => value,
+        }
+    }
+
+    fn inline(&self, value: &Node, lines: &Node, format: TimeFormat) -> Node {
+        match (value, lines) {
+            (Node::Leaf(v), Node::Leaf(TypedValue::Integer(line))) => {
+                Node::Dict(self.meta_map(Some(v), *line, format))
+            }
+            (Node::Dict(values), Node::Dict(lines)) => {
+                Node::Dict(self.inline_map(values, lines, format))
+            }
+            (Node::Array(values), Node::Array(lines)) => Node::Array(
+                values
+                    .iter()
+                    .zip(lines)
+                    .map(|(v, lines)| self.inline(v, lines, format))
+                    .collect(),
+            ),
+            _ => value.clone(),
+        }
+    }
+
+    fn inline_map(&self, values: &MapNodes, lines: &MapNodes, format: TimeFormat) -> MapNodes {
+        let mut m = MapNodes::new();
+        for (k, v) in &values.nodes {
+            let node = match lines.nodes.get(k) {
+                Some(lines) => self.inline(v, lines, format),
+                None => v.clone(),
+            };
+            m.nodes.insert(k.clone(), node);
+        }
+        m
+    }
+
+    fn subtree(&self, lines: &Node, format: TimeFormat) -> Node {
+        match lines {
+            Node::Leaf(TypedValue::Integer(line)) => Node::Dict(self.meta_map(None, *line, format)),
+            Node::Leaf(_) => lines.clone(),
+            Node::Dict(lines) => {
+                let mut m = MapNodes::new();
+                for (k, v) in &lines.nodes {
+                    m.nodes.insert(k.clone(), self.subtree(v, format));
+                }
+                Node::Dict(m)
+            }
+            Node::Array(a) => Node::Array(a.iter().map(|n| self.subtree(n, format)).collect()),
+        }
+    }
+
+    fn meta_map(&self, value: Option<&TypedValue>, line: i64, format: TimeFormat) -> MapNodes {
+        let mut m = MapNodes::new();
+        if let Some(value) = value {
+            m.nodes
+                .insert(VALUE_KEY.to_string(), Node::Leaf(value.clone()));
+        }
+        let time = match (self.times.get(&line), format) {
+            (Some(t), TimeFormat::Canonical) => TypedValue::String(t.to_canonical()),
+            (Some(t), _) => t.to_epoch(),
+            (None, _) => TypedValue::Null,
+        };
+        m.nodes.insert(TIME_KEY.to_string(), Node::Leaf(time));
+        m.nodes
+            .insert(LINE_KEY.to_string(), Node::Leaf(TypedValue::Integer(line)));
+        m
+    }
+}
+
+/// A node of the same shape, its leaves replaced by the line number
+fn mirror_leaves(node: &Node, line: i64) -> Node {
+    match node {
+        Node::Leaf(_) => Node::Leaf(TypedValue::Integer(line)),
+        Node::Dict(m) => {
+            let mut mirrored = MapNodes::new();
+            for (k, v) in &m.nodes {
+                mirrored.nodes.insert(k.clone(), mirror_leaves(v, line));
+            }
+            Node::Dict(mirrored)
+        }
+        Node::Array(a) => Node::Array(a.iter().map(|n| mirror_leaves(n, line)).collect()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn meta() -> Meta {
+        let mut meta = Meta {
+            lines: serde_json::from_value(json!({"a": [3, 5], "b": {"c": 4}})).unwrap(),
+            ..Default::default()
+        };
+        // line n arrived n seconds after the epoch
+        for line in [3, 4, 5] {
+            meta.times
+                .insert(line, Timestamp::from_epoch(&line.to_string()).unwrap());
+        }
+        meta
+    }
+
+    fn render(mode: MetaMode, format: TimeFormat) -> serde_json::Value {
+        let values: MapNodes =
+            serde_json::from_value(json!({"a": [1, 2], "b": {"c": true}, "d": 0})).unwrap();
+        serde_json::to_value(meta().render(&values, mode, format)).unwrap()
+    }
+
+    #[test]
+    fn test_mirror() {
+        let node: Node = serde_json::from_value(json!({"a": [1, "x"], "b": {"c": null}})).unwrap();
+        let mirrored = serde_json::to_value(mirror_leaves(&node, 7)).unwrap();
+        assert_eq!(mirrored, json!({"a": [7, 7], "b": {"c": 7}}));
+    }
+
+    #[test]
+    fn test_inline() {
+        let correct_json = json!({
+            "a": [{"v": 1, "t": 3, "line": 3}, {"v": 2, "t": 5, "line": 5}],
+            "b": {"c": {"v": true, "t": 4, "line": 4}},
+            "d": 0
+        });
+        assert_eq!(render(MetaMode::Inline, TimeFormat::Keep), correct_json);
+    }
+
+    #[test]
+    fn test_subtree() {
+        let correct_json = json!({
+            "a": [1, 2],
+            "b": {"c": true},
+            "d": 0,
+            "_meta": {
+                "a": [{"t": "1970-01-01T00:00:03Z", "line": 3}, {"t": "1970-01-01T00:00:05Z", "line": 5}],
+                "b": {"c": {"t": "1970-01-01T00:00:04Z", "line": 4}}
+            }
+        });
+        assert_eq!(
+            render(MetaMode::Subtree, TimeFormat::Canonical),
+            correct_json
+        );
+    }
+}
@@ -0,0 +1,349 @@
+use crate::engine::engine_options::TimeFormat;
+use crate::jsonmodels::TypedValue;
+use regex::Regex;
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+
+/// A point in time, as seconds and nanoseconds since the Unix epoch in UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timestamp {
+    pub seconds: i64,
+    pub nanos: u32,
+}
+
+impl Timestamp {
+    pub fn now() -> Self {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => Timestamp {
+                seconds: d.as_secs() as i64,
+                nanos: d.subsec_nanos(),
+            },
+            Err(e) => Self::from_nanos(-(e.duration().as_nanos() as i128)).unwrap(),
+        }
+    }
+
+    fn from_nanos(nanos: i128) -> Option<Self> {
+        let seconds = i64::try_from(nanos.div_euclid(NANOS_PER_SECOND as i128)).ok()?;
+        let nanos = nanos.rem_euclid(NANOS_PER_SECOND as i128) as u32;
+        Some(Timestamp { seconds, nanos })
+    }
+
+    /// Read Unix epoch seconds, ie `1700000000` or `1700000000.25`
+    pub fn from_epoch(value: &str) -> Option<Self> {
+        Self::from_epoch_in(value, NANOS_PER_SECOND)
+    }
+
+    /// Read Unix epoch milliseconds, ie `1700000000250`
+    pub fn from_epoch_ms(value: &str) -> Option<Self> {
+        Self::from_epoch_in(value, NANOS_PER_SECOND / 1000)
+    }
+
+    fn from_epoch_in(value: &str, nanos_per_unit: i64) -> Option<Self> {
+        // integers are read exactly, floats are rounded to the nanosecond
+        if let Ok(i) = value.parse::<i64>() {
+            return Self::from_nanos(i as i128 * nanos_per_unit as i128);
+        }
+        let f = value.parse::<f64>().ok().filter(|f| f.is_finite())?;
+        let nanos = f * nanos_per_unit as f64;
+        if nanos.abs() >= i128::MAX as f64 {
+            return None;
+        }
+        Self::from_nanos(nanos.round() as i128)
+    }
+
+    /// Read an ISO-8601 date or date and time, ie `2024-03-01`,
+    /// `2024-03-01T12:30:00Z` or `2024-03-01 12:30:00.250+02:00`. A time
+    /// without offset is in UTC.
+    pub fn from_iso8601(value: &str) -> Option<Self> {
+        static ISO8601: OnceLock<Regex> = OnceLock::new();
+        let re = ISO8601.get_or_init(|| {
+            Regex::new(
+                r"^([0-9]{4})-([0-9]{2})-([0-9]{2})(?:[Tt ]([0-9]{2}):([0-9]{2})(?::([0-9]{2})(?:[.,]([0-9]{1,9}))?)?(?:([Zz])|([+-])([0-9]{2}):?([0-9]{2})?)?)?$",
+            )
+            .unwrap()
+        });
+        let caps = re.captures(value)?;
+        // the digits are ascii, a missing part being 0
+        let number = |i: usize| {
+            caps.get(i)
+                .map_or(Some(0), |m| m.as_str().parse::<i64>().ok())
+        };
+
+        let (year, month, day) = (number(1)?, number(2)?, number(3)?);
+        if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+            return None;
+        }
+        let (hour, minute, second) = (number(4)?, number(5)?, number(6)?);
+        if hour > 23 || minute > 59 || second > 59 {
+            return None;
+        }
+        let nanos = match caps.get(7) {
+            // right padded, ie `25` is 250_000_000 nanoseconds
+            Some(m) => format!("{:0<9}", m.as_str()).parse::<i64>().ok()?,
+            None => 0,
+        };
+        let offset_minutes = match caps.get(9).map(|m| m.as_str()) {
+            Some(sign) => {
+                let (hours, minutes) = (number(10)?, number(11)?);
+                if hours > 23 || minutes > 59 {
+                    return None;
+                }
+                let offset = hours * 60 + minutes;
+                if sign == "-" {
+                    -offset
+                } else {
+                    offset
+                }
+            }
+            None => 0,
+        };
+
+        let seconds =
+            days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second
+                - offset_minutes * 60;
+        Some(Timestamp {
+            seconds,
+            nanos: nanos as u32,
+        })
+    }
+
+    /// RFC 3339 in UTC, ie `2024-03-01T10:30:00.25Z`
+    pub fn to_canonical(self) -> String {
+        let days = self.seconds.div_euclid(86400);
+        let seconds_of_day = self.seconds.rem_euclid(86400);
+        let (year, month, day) = civil_from_days(days);
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}Z",
+            year,
+            month,
+            day,
+            seconds_of_day / 3600,
+            seconds_of_day / 60 % 60,
+            seconds_of_day % 60,
+            fraction(self.nanos)
+        )
+    }
+
+    /// Seconds since the Unix epoch, an integer unless there are fractions of seconds
+    pub fn to_epoch(self) -> TypedValue {
+        if self.nanos == 0 {
+            TypedValue::Integer(self.seconds)
+        } else {
+            TypedValue::Float(self.seconds as f64 + self.nanos as f64 / 1e9)
+        }
+    }
+
+    /// The timestamp written with the time format, `text` being the value as printed
+    pub fn to_typed_value(self, text: &str, format: TimeFormat) -> TypedValue {
+        match format {
+            TimeFormat::Keep => TypedValue::from_number_or_string(text.to_string()),
+            TimeFormat::Canonical => TypedValue::String(self.to_canonical()),
+            TimeFormat::Epoch => self.to_epoch(),
+        }
+    }
+}
+
+/// Read a duration made of numbers followed by `d`, `h`, `m` (or `min`), `s`,
+/// `ms`, `us` (or `µs`) and `ns`, ie `1h2m3s` or `1.5s`
+pub fn parse_duration(value: &str) -> Option<Duration> {
+    static PART: OnceLock<Regex> = OnceLock::new();
+    let re =
+        PART.get_or_init(|| Regex::new(r"([0-9]+(?:\.[0-9]+)?)(min|ms|us|µs|ns|d|h|m|s)").unwrap());
+
+    let mut end = 0;
+    let mut nanos = 0.0;
+    for caps in re.captures_iter(value) {
+        let part = caps.get(0).unwrap();
+        // the parts must follow each other
+        if part.start() != end {
+            return None;
+        }
+        end = part.end();
+        let number: f64 = caps[1].parse().ok()?;
+        let unit_nanos = match &caps[2] {
+            "d" => 86400e9,
+            "h" => 3600e9,
+            "m" | "min" => 60e9,
+            "s" => 1e9,
+            "ms" => 1e6,
+            "us" | "µs" => 1e3,
+            _ => 1.0,
+        };
+        nanos += number * unit_nanos;
+    }
+    if end == 0 || end != value.len() || nanos >= u64::MAX as f64 {
+        return None;
+    }
+    Some(Duration::from_nanos(nanos.round() as u64))
+}
+
+/// The duration written with the time format, `text` being the value as printed.
+/// The epoch format gives a number of seconds.
+pub fn duration_to_typed_value(duration: Duration, text: &str, format: TimeFormat) -> TypedValue {
+    match format {
+        TimeFormat::Keep => TypedValue::String(text.to_string()),
+        TimeFormat::Canonical => TypedValue::String(duration_to_iso8601(duration)),
+        TimeFormat::Epoch => match duration.subsec_nanos() {
+            0 => TypedValue::Integer(duration.as_secs() as i64),
+            _ => TypedValue::Float(duration.as_secs_f64()),
+        },
+    }
+}
+
+/// ISO-8601 duration, ie `PT1H2M3S` or `P1DT0.5S`
+fn duration_to_iso8601(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    let (days, hours, minutes) = (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60);
+    let seconds = seconds % 60;
+    let mut iso = "P".to_string();
+    if days > 0 {
+        iso += &format!("{}D", days);
+    }
+    let mut time = String::new();
+    if hours > 0 {
+        time += &format!("{}H", hours);
+    }
+    if minutes > 0 {
+        time += &format!("{}M", minutes);
+    }
+    if seconds > 0 || duration.subsec_nanos() > 0 || (days == 0 && time.is_empty()) {
+        time += &format!("{}{}S", seconds, fraction(duration.subsec_nanos()));
+    }
+    if !time.is_empty() {
+        iso += "T";
+        iso += &time;
+    }
+    iso
+}
+
+/// Fractional part of a second, without trailing zeros, ie `.25`
+fn fraction(nanos: u32) -> String {
+    if nanos == 0 {
+        return String::new();
+    }
+    format!(".{:09}", nanos).trim_end_matches('0').to_string()
+}
+
+fn is_leap_year(year: i64) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Date of the proleptic Gregorian calendar from days since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn iso(value: &str) -> Option<String> {
+        Timestamp::from_iso8601(value).map(|t| t.to_canonical())
+    }
+
+    #[test]
+    fn test_civil() {
+        assert_eq!(days_from_civil(1970, 1, 1), 0);
+        assert_eq!(days_from_civil(2000, 3, 1), 11017);
+        assert_eq!(days_from_civil(1969, 12, 31), -1);
+        for days in [-800000, -1, 0, 11017, 19782, 800000] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+    }
+
+    #[test]
+    fn test_iso8601() {
+        assert_eq!(
+            Timestamp::from_iso8601("1970-01-01T00:00:01Z"),
+            Some(Timestamp {
+                seconds: 1,
+                nanos: 0
+            })
+        );
+        assert_eq!(iso("2024-02-29"), Some("2024-02-29T00:00:00Z".to_string()));
+        assert_eq!(
+            iso("2024-03-01 12:30:00.25+02:00"),
+            Some("2024-03-01T10:30:00.25Z".to_string())
+        );
+        assert_eq!(
+            iso("2024-03-01T00:30-0100"),
+            Some("2024-03-01T01:30:00Z".to_string())
+        );
+        assert_eq!(
+            iso("2024-01-01T00:00:00+01"),
+            Some("2023-12-31T23:00:00Z".to_string())
+        );
+        assert_eq!(iso("2023-02-29"), None);
+        assert_eq!(iso("2024-13-01"), None);
+        assert_eq!(iso("2024-01-01T24:00:00"), None);
+        assert_eq!(iso("2024-01-01Z"), None);
+        assert_eq!(iso("20240101"), None);
+        // only ascii digits, the arabic-indic ones being left as text
+        assert_eq!(iso("٢٠٢٤-٠٣-٠١"), None);
+        assert_eq!(iso("2024-03-01T12:٣٠"), None);
+    }
+
+    #[test]
+    fn test_epoch() {
+        let t = Timestamp::from_epoch("1700000000").unwrap();
+        assert_eq!(t.to_canonical(), "2023-11-14T22:13:20Z");
+        assert_eq!(t.to_epoch(), TypedValue::Integer(1700000000));
+        let t = Timestamp::from_epoch_ms("1700000000250").unwrap();
+        assert_eq!(t.to_canonical(), "2023-11-14T22:13:20.25Z");
+        assert_eq!(t.to_epoch(), TypedValue::Float(1700000000.25));
+        let t = Timestamp::from_epoch("-1.5").unwrap();
+        assert_eq!(t.to_canonical(), "1969-12-31T23:59:58.5Z");
+        assert_eq!(Timestamp::from_epoch("soon"), None);
+    }
+
+    #[test]
+    fn test_duration() {
+        let iso = |s: &str| parse_duration(s).map(duration_to_iso8601);
+        assert_eq!(parse_duration("1h2m3s"), Some(Duration::from_secs(3723)));
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("2min"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("3µs"), Some(Duration::from_micros(3)));
+        assert_eq!(iso("1d2h3m4s"), Some("P1DT2H3M4S".to_string()));
+        assert_eq!(iso("1d"), Some("P1D".to_string()));
+        assert_eq!(iso("90.5s"), Some("PT1M30.5S".to_string()));
+        assert_eq!(iso("0s"), Some("PT0S".to_string()));
+        assert_eq!(parse_duration("1h 2m"), None);
+        assert_eq!(parse_duration("1x"), None);
+        assert_eq!(parse_duration("12"), None);
+        assert_eq!(parse_duration("٥s"), None);
+        assert_eq!(parse_duration(""), None);
+    }
+}
@@ -0,0 +1,172 @@
+use crate::jsonmodels::{MapNodes, Node};
+
+/// A step from a node to one of its children
+#[derive(Debug, Clone, PartialEq)]
+pub enum Location {
+    Key(String),
+    Index(usize),
+}
+
+/// How to undo a change, at the node (or the map) which was changed
+#[derive(Debug)]
+enum Undo {
+    /// A key was added to the map
+    RemoveKey(String),
+    /// The node replaced this one
+    Restore(Node),
+    /// The node was wrapped in a new one, ie a leaf becoming the first element
+    /// of an array, and is found at this location of the new one
+    Unwrap(Location),
+    /// Elements were pushed to the array, which had this length
+    Truncate(usize),
+}
+
+/// Undo log of the changes made to a tree, so that a line failing half
+/// inserted is rolled back without copying the tree beforehand.
+///
+/// The merge functions [enter](Trail::enter) the nodes they go through and
+/// record each change at the current location. The changes are undone in the
+/// reverse order, each location being found again as it was when recorded.
+#[derive(Debug, Default)]
+pub struct Trail {
+    /// Location of the node being changed, from the root
+    location: Vec<Location>,
+    steps: Vec<(Vec<Location>, Undo)>,
+}
+
+impl Trail {
+    pub fn enter(&mut self, location: Location) {
+        self.location.push(location);
+    }
+
+    pub fn leave(&mut self) {
+        self.location.pop();
+    }
+
+    /// `key` was added to the map at the current location
+    pub fn key_added(&mut self, key: &str) {
+        self.record(Undo::RemoveKey(key.to_string()));
+    }
+
+    /// The node at the current location replaced `old`
+    pub fn replaced(&mut self, old: Node) {
+        self.record(Undo::Restore(old));
+    }
+
+    /// The node at the current location was wrapped in a new node, being
+    /// found at `location` of the new one
+    pub fn wrapped(&mut self, location: Location) {
+        self.record(Undo::Unwrap(location));
+    }
+
+    /// Elements are about to be pushed to the array at the current location,
+    /// having `len` elements
+    pub fn appended(&mut self, len: usize) {
+        self.record(Undo::Truncate(len));
+    }
+
+    fn record(&mut self, undo: Undo) {
+        self.steps.push((self.location.clone(), undo));
+    }
+
+    /// Undo the changes recorded, `root` being the tree they were made to
+    pub fn rollback(self, root: &mut MapNodes) {
+        for (location, undo) in self.steps.into_iter().rev() {
+            if let Undo::RemoveKey(k) = undo {
+                match map_at(root, &location) {
+                    Some(map) => map.nodes.shift_remove(&k),
+                    None => unreachable!("no map at {:?}", location),
+                };
+                continue;
+            }
+            let Some(node) = node_at(root, &location) else {
+                unreachable!("no node at {:?}", location)
+            };
+            let old = match (undo, &mut *node) {
+                (Undo::Restore(old), _) => old,
+                (Undo::Unwrap(Location::Key(k)), Node::Dict(m)) if m.nodes.contains_key(&k) => {
+                    m.nodes.shift_remove(&k).unwrap()
+                }
+                (Undo::Unwrap(Location::Index(i)), Node::Array(a)) if i < a.len() => {
+                    a.swap_remove(i)
+                }
+                (Undo::Truncate(len), Node::Array(a)) => {
+                    a.truncate(len);
+                    continue;
+                }
+                (undo, node) => unreachable!("cannot undo {:?} on {:?}", undo, node),
+            };
+            *node = old;
+        }
+    }
+}
+
+fn node_at<'a>(root: &'a mut MapNodes, location: &[Location]) -> Option<&'a mut Node> {
+    let (Location::Key(k), rest) = location.split_first()? else {
+        return None;
+    };
+    let mut node = root.nodes.get_mut(k)?;
+    for step in rest {
+        node = match (step, node) {
+            (Location::Key(k), Node::Dict(m)) => m.nodes.get_mut(k)?,
+            (Location::Index(i), Node::Array(a)) => a.get_mut(*i)?,
+            _ => return None,
+        };
+    }
+    Some(node)
+}
+
+fn map_at<'a>(root: &'a mut MapNodes, location: &[Location]) -> Option<&'a mut MapNodes> {
+    if location.is_empty() {
+        return Some(root);
+    }
+    match node_at(root, location)? {
+        Node::Dict(m) => Some(m),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn rollback() {
+        let before: MapNodes = serde_json::from_value(json!({"a": 1, "b": {"c": [1, 2]}})).unwrap();
+        let mut root = before.clone();
+        let mut trail = Trail::default();
+
+        // a becomes [1, 2]
+        trail.enter(Location::Key("a".to_string()));
+        let Some(Node::Leaf(a)) = root.nodes.get("a").cloned() else {
+            panic!()
+        };
+        root.nodes.insert(
+            "a".to_string(),
+            Node::Array(vec![Node::Leaf(a.clone()), Node::Leaf(a)]),
+        );
+        trail.wrapped(Location::Index(0));
+        trail.leave();
+
+        // b.c gets a third element, then b.d is added
+        trail.enter(Location::Key("b".to_string()));
+        let Some(Node::Dict(b)) = root.nodes.get_mut("b") else {
+            panic!()
+        };
+        trail.enter(Location::Key("c".to_string()));
+        let Some(Node::Array(c)) = b.nodes.get_mut("c") else {
+            panic!()
+        };
+        trail.appended(c.len());
+        c.push(Node::Dict(MapNodes::new()));
+        trail.leave();
+        b.nodes.insert("d".to_string(), Node::Array(Vec::new()));
+        trail.key_added("d");
+        trail.leave();
+
+        assert_ne!(root, before);
+        trail.rollback(&mut root);
+        assert_eq!(root, before);
+    }
+}
@@ -0,0 +1,207 @@
+use crate::engine::engine_options::UnitMode;
+use crate::engine::EngineOptions;
+use crate::jsonmodels::{MapNodes, Node, TypedValue};
+use indexmap::IndexMap;
+
+/// Conversion of a unit to its SI unit: `si = value * factor + offset`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Unit {
+    pub factor: f64,
+    pub offset: f64,
+}
+
+impl Unit {
+    pub fn new(factor: f64) -> Self {
+        Unit {
+            factor,
+            offset: 0.0,
+        }
+    }
+}
+
+/// Units known by default, by symbol
+pub fn default_units() -> IndexMap<String, Unit> {
+    let mut units = IndexMap::new();
+    let mut add = |symbol: &str, unit: Unit| {
+        units.insert(symbol.to_string(), unit);
+    };
+    // time
+    add("ns", Unit::new(1e-9));
+    add("us", Unit::new(1e-6));
+    add("µs", Unit::new(1e-6));
+    add("ms", Unit::new(1e-3));
+    add("s", Unit::new(1.0));
+    add("min", Unit::new(60.0));
+    add("h", Unit::new(3600.0));
+    // sizes, in bytes
+    add("B", Unit::new(1.0));
+    add("KB", Unit::new(1e3));
+    add("MB", Unit::new(1e6));
+    add("GB", Unit::new(1e9));
+    add("TB", Unit::new(1e12));
+    add("KiB", Unit::new(1024.0));
+    add("MiB", Unit::new(1024.0 * 1024.0));
+    add("GiB", Unit::new(1024.0 * 1024.0 * 1024.0));
+    add("TiB", Unit::new(1024.0 * 1024.0 * 1024.0 * 1024.0));
+    // temperatures, in kelvins
+    add("K", Unit::new(1.0));
+    add(
+        "C",
+        Unit {
+            factor: 1.0,
+            offset: 273.15,
+        },
+    );
+    // lengths
+    add("mm", Unit::new(1e-3));
+    add("cm", Unit::new(1e-2));
+    add("m", Unit::new(1.0));
+    add("km", Unit::new(1e3));
+    // electricity
+    add("mV", Unit::new(1e-3));
+    add("V", Unit::new(1.0));
+    add("mA", Unit::new(1e-3));
+    add("A", Unit::new(1.0));
+    add("mW", Unit::new(1e-3));
+    add("W", Unit::new(1.0));
+    add("kW", Unit::new(1e3));
+    // frequencies
+    add("Hz", Unit::new(1.0));
+    add("kHz", Unit::new(1e3));
+    add("MHz", Unit::new(1e6));
+    add("GHz", Unit::new(1e9));
+    units
+}
+
+/// Read a number followed by a known unit, ie `21.5C` or `12 ms`, according
+/// to the unit mode. Returns `None` when the value isn't such a number.
+pub fn parse_with_unit(value: &str, options: &EngineOptions) -> Option<Node> {
+    if matches!(options.unit_mode, UnitMode::Off) {
+        return None;
+    }
+    let (number, symbol) = split_number(value)?;
+    let unit = options.units.get(symbol)?;
+    let number_value: f64 = number.parse().ok()?;
+    if !number_value.is_finite() {
+        return None;
+    }
+
+    match options.unit_mode {
+        UnitMode::Off => None,
+        UnitMode::Object => {
+            let mut m = MapNodes::new();
+            m.nodes.insert(
+                "value".to_string(),
+                Node::Leaf(TypedValue::from_number_or_string(number.to_string())),
+            );
+            m.nodes.insert(
+                "unit".to_string(),
+                Node::Leaf(TypedValue::String(symbol.to_string())),
+            );
+            Some(Node::Dict(m))
+        }
+        UnitMode::Normalize => {
+            let si = number_value * unit.factor + unit.offset;
+            Some(Node::Leaf(number_to_typed_value(si)))
+        }
+    }
+}
+
+/// Split the longest decimal number at the start of the value from the rest,
+/// ignoring the spaces in between
+fn split_number(value: &str) -> Option<(&str, &str)> {
+    let bytes = value.as_bytes();
+    let mut end = 0;
+    if matches!(bytes.first(), Some(b'+' | b'-')) {
+        end += 1;
+    }
+    let digits_start = end;
+    while end < bytes.len() && (bytes[end].is_ascii_digit() || bytes[end] == b'.') {
+        end += 1;
+    }
+    if !bytes[digits_start..end].iter().any(u8::is_ascii_digit) {
+        return None;
+    }
+    // an exponent, only if followed by digits so that `Em` or `e` stay units
+    if matches!(bytes.get(end), Some(b'e' | b'E')) {
+        let mut exponent_end = end + 1;
+        if matches!(bytes.get(exponent_end), Some(b'+' | b'-')) {
+            exponent_end += 1;
+        }
+        if bytes.get(exponent_end).is_some_and(u8::is_ascii_digit) {
+            end = exponent_end;
+            while end < bytes.len() && bytes[end].is_ascii_digit() {
+                end += 1;
+            }
+        }
+    }
+    let symbol = value[end..].trim_start();
+    if symbol.is_empty() {
+        return None;
+    }
+    Some((&value[..end], symbol))
+}
+
+/// Integral values are written as integers, ie `4096` rather than `4096.0`
+fn number_to_typed_value(f: f64) -> TypedValue {
+    const MAX_EXACT: f64 = (1u64 << 53) as f64;
+    if f.fract() == 0.0 && f.abs() <= MAX_EXACT {
+        TypedValue::Integer(f as i64)
+    } else {
+        TypedValue::Float(f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn to_json(value: &str, options: &EngineOptions) -> Option<serde_json::Value> {
+        parse_with_unit(value, options).map(|n| serde_json::to_value(n).unwrap())
+    }
+
+    #[test]
+    fn test_split_number() {
+        assert_eq!(split_number("21.5C"), Some(("21.5", "C")));
+        assert_eq!(split_number("-12 ms"), Some(("-12", "ms")));
+        assert_eq!(split_number("1e3Hz"), Some(("1e3", "Hz")));
+        assert_eq!(split_number("1Em"), Some(("1", "Em")));
+        assert_eq!(split_number("12"), None);
+        assert_eq!(split_number("ms"), None);
+        assert_eq!(split_number("-.C"), None);
+    }
+
+    #[test]
+    fn test_off() {
+        let options = EngineOptions::new();
+        assert_eq!(to_json("21.5C", &options), None);
+    }
+
+    #[test]
+    fn test_object() {
+        let options = EngineOptions::new().with_unit_mode(UnitMode::Object);
+        assert_eq!(
+            to_json("21.5C", &options),
+            Some(json!({"value": 21.5, "unit": "C"}))
+        );
+        assert_eq!(
+            to_json("4 KiB", &options),
+            Some(json!({"value": 4, "unit": "KiB"}))
+        );
+        assert_eq!(to_json("4 apples", &options), None);
+        assert_eq!(to_json("1.2.3ms", &options), None);
+    }
+
+    #[test]
+    fn test_normalize() {
+        let options = EngineOptions::new()
+            .with_unit_mode(UnitMode::Normalize)
+            .with_unit("rpm".to_string(), 1.0 / 60.0);
+        assert_eq!(to_json("12ms", &options), Some(json!(0.012)));
+        assert_eq!(to_json("4KiB", &options), Some(json!(4096)));
+        assert_eq!(to_json("2h", &options), Some(json!(7200)));
+        assert_eq!(to_json("20C", &options), Some(json!(293.15)));
+        assert_eq!(to_json("120rpm", &options), Some(json!(2)));
+    }
+}
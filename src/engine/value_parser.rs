@@ -0,0 +1,623 @@
+use crate::engine::engine_options::{TimeFormat, UnitMode};
+use crate::engine::errors::{Error, Result};
+use crate::engine::time::{duration_to_typed_value, parse_duration, Timestamp};
+use crate::engine::units::parse_with_unit;
+use crate::engine::EngineOptions;
+use crate::jsonmodels::{BigInteger, Node, TypedValue};
+use num_bigint::BigInt;
+use std::borrow::Cow;
+use std::str::FromStr;
+
+/// Type forced by an annotation on the key, ie `str` in `;zip@str:01234`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ValueType {
+    /// `str`, `string` or `s`
+    String,
+    /// `int`, `i64` or `i`
+    Integer,
+    /// `uint`, `u64` or `u`
+    UInteger,
+    /// `float`, `f64` or `f`
+    Float,
+    /// `bool` or `b`
+    Bool,
+    /// `json`: the value is a json document grafted in the tree
+    Json,
+    /// `time` or `timestamp`: an ISO-8601 date and time
+    Timestamp,
+    /// `epoch`: seconds since the Unix epoch
+    Epoch,
+    /// `epoch_ms`: milliseconds since the Unix epoch
+    EpochMs,
+    /// `duration`, ie `1h2m3s`
+    Duration,
+}
+
+impl FromStr for ValueType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "str" | "string" | "s" => Ok(ValueType::String),
+            "int" | "i64" | "i" => Ok(ValueType::Integer),
+            "uint" | "u64" | "u" => Ok(ValueType::UInteger),
+            "float" | "f64" | "f" => Ok(ValueType::Float),
+            "bool" | "b" => Ok(ValueType::Bool),
+            "json" => Ok(ValueType::Json),
+            "time" | "timestamp" => Ok(ValueType::Timestamp),
+            "epoch" => Ok(ValueType::Epoch),
+            "epoch_ms" => Ok(ValueType::EpochMs),
+            "duration" => Ok(ValueType::Duration),
+            _ => Err(Error::InvalidKey(format!(
+                "unknown type annotation '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+/// Convert the value of a line, ie `1` in `;a.b:1`, to a node.
+/// `value_type` is the annotation found on the key, if any.
+pub fn parse_value(value: &str, value_type: Option<&str>, options: &EngineOptions) -> Result<Node> {
+    let value = value.trim();
+    let value_type = value_type.map(ValueType::from_str).transpose()?;
+    if value_type == Some(ValueType::Json) {
+        return parse_json(value);
+    }
+    if let Some(unquoted) = unquote(value)? {
+        let typed_value = match value_type {
+            None | Some(ValueType::String) => TypedValue::String(unquoted),
+            Some(value_type) => convert(&unquoted, value_type, options)?,
+        };
+        return Ok(Node::Leaf(typed_value));
+    }
+
+    let typed_value = match value_type {
+        Some(value_type) => convert(value, value_type, options)?,
+        None if options.infer_types => {
+            let typed_value = infer(value, options);
+            if let TypedValue::String(_) = typed_value {
+                if let Some(node) = parse_with_unit(value, options) {
+                    return Ok(node);
+                }
+            }
+            typed_value
+        }
+        None => TypedValue::String(value.to_string()),
+    };
+    Ok(Node::Leaf(typed_value))
+}
+
+/// Terminator of a multi-line value, ie `EOF` for `<<EOF`
+pub fn heredoc_terminator(value: &str) -> Option<&str> {
+    let terminator = value.trim().strip_prefix("<<")?;
+    let mut chars = terminator.chars();
+    let first = chars.next()?;
+    if (first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        Some(terminator)
+    } else {
+        None
+    }
+}
+
+/// Convert the text of a multi-line value, kept as it is unless annotated
+pub fn parse_block_value(
+    text: &str,
+    value_type: Option<&str>,
+    options: &EngineOptions,
+) -> Result<Node> {
+    match value_type.map(ValueType::from_str).transpose()? {
+        None | Some(ValueType::String) => Ok(Node::Leaf(TypedValue::String(text.to_string()))),
+        Some(_) => parse_value(text, value_type, options),
+    }
+}
+
+fn convert(value: &str, value_type: ValueType, options: &EngineOptions) -> Result<TypedValue> {
+    let invalid = || Error::InvalidValue(format!("'{}' is not a {:?}", value, value_type));
+    match value_type {
+        ValueType::String => Ok(TypedValue::String(value.to_string())),
+        ValueType::Integer => parse_integer(value, options)
+            .and_then(|i| narrow_integer(i, options))
+            .ok_or_else(invalid),
+        ValueType::UInteger => parse_integer(value, options)
+            .and_then(|i| u64::try_from(i).ok())
+            .map(TypedValue::UInteger)
+            .ok_or_else(invalid),
+        ValueType::Float => match f64::from_str(&remove_digit_separators(value, options)) {
+            Ok(f) if f.is_finite() => Ok(TypedValue::Float(f)),
+            Ok(_) if options.non_finite_as_null => Ok(TypedValue::Null),
+            _ => Err(invalid()),
+        },
+        ValueType::Bool => match infer_word(value, options) {
+            Some(TypedValue::Bool(b)) => Ok(TypedValue::Bool(b)),
+            _ => Err(invalid()),
+        },
+        ValueType::Timestamp => Timestamp::from_iso8601(value)
+            .map(|t| t.to_typed_value(value, options.time_format))
+            .ok_or_else(invalid),
+        ValueType::Epoch => Timestamp::from_epoch(value)
+            .map(|t| t.to_typed_value(value, options.time_format))
+            .ok_or_else(invalid),
+        ValueType::EpochMs => Timestamp::from_epoch_ms(value)
+            .map(|t| t.to_typed_value(value, options.time_format))
+            .ok_or_else(invalid),
+        ValueType::Duration => parse_duration(value)
+            .map(|d| duration_to_typed_value(d, value, options.time_format))
+            .ok_or_else(invalid),
+        ValueType::Json => unreachable!("json values are handled by parse_json"),
+    }
+}
+
+/// Read a double quoted value with C escapes, ie `"a\tb\n"`.
+/// Returns `None` when the value isn't entirely quoted.
+fn unquote(value: &str) -> Result<Option<String>> {
+    let Some(content) = value.strip_prefix('"') else {
+        return Ok(None);
+    };
+    // the closing quote must end the value
+    let mut chars = content.char_indices();
+    let mut end = None;
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '\\' => {
+                chars.next();
+            }
+            '"' => {
+                end = Some(i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    match end {
+        Some(end) if end + 1 == content.len() => unescape(&content[..end]).map(Some),
+        _ => Ok(None),
+    }
+}
+
+fn unescape(s: &str) -> Result<String> {
+    let invalid = |reason: &str| Error::InvalidValue(format!("\"{}\": {}", s, reason));
+    let mut unescaped = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        let c = match chars.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('a') => '\x07',
+            Some('b') => '\x08',
+            Some('f') => '\x0c',
+            Some('v') => '\x0b',
+            Some(c @ ('\\' | '"' | '\'' | '?')) => c,
+            Some('x') => read_hex(&mut chars, 2).ok_or_else(|| invalid("expected \\xHH"))?,
+            Some('u') => read_hex(&mut chars, 4).ok_or_else(|| invalid("expected \\uHHHH"))?,
+            Some(c) => return Err(invalid(&format!("unknown escape \\{}", c))),
+            None => return Err(invalid("nothing to escape after \\")),
+        };
+        unescaped.push(c);
+    }
+    Ok(unescaped)
+}
+
+fn read_hex(chars: &mut std::str::Chars, digits: usize) -> Option<char> {
+    let hex: String = chars.take(digits).collect();
+    if hex.len() != digits {
+        return None;
+    }
+    u32::from_str_radix(&hex, 16).ok().and_then(char::from_u32)
+}
+
+fn parse_json(value: &str) -> Result<Node> {
+    serde_json::from_str::<serde_json::Value>(value)
+        .map(Node::from)
+        .map_err(|e| Error::InvalidValue(format!("'{}' is not valid json: {}", value, e)))
+}
+
+fn infer(value: &str, options: &EngineOptions) -> TypedValue {
+    if let Some(typed_value) = infer_word(value, options) {
+        return typed_value;
+    }
+    if options.non_finite_as_null && f64::from_str(value).is_ok_and(|f| !f.is_finite()) {
+        return TypedValue::Null;
+    }
+    if let Some(typed_value) =
+        parse_integer(value, options).and_then(|i| narrow_integer(i, options))
+    {
+        return typed_value;
+    }
+    match TypedValue::from_number_or_string(remove_digit_separators(value, options).into_owned()) {
+        TypedValue::String(_) => infer_time(value, options),
+        typed_value => typed_value,
+    }
+}
+
+/// Read timestamps and durations, unless they are kept as they are. When the
+/// units are read too, a number followed by a single unit is left to them, ie
+/// `5m` is 5 meters and `1h2m` a duration.
+fn infer_time(value: &str, options: &EngineOptions) -> TypedValue {
+    if options.time_format != TimeFormat::Keep {
+        if let Some(t) = Timestamp::from_iso8601(value) {
+            return t.to_typed_value(value, options.time_format);
+        }
+        let is_unit =
+            || options.unit_mode != UnitMode::Off && parse_with_unit(value, options).is_some();
+        if let Some(d) = parse_duration(value).filter(|_| !is_unit()) {
+            return duration_to_typed_value(d, value, options.time_format);
+        }
+    }
+    TypedValue::String(value.to_string())
+}
+
+/// Read an integer, with a radix prefix and digit separators if enabled
+fn parse_integer(value: &str, options: &EngineOptions) -> Option<BigInt> {
+    let value = remove_digit_separators(value, options);
+    let (negative, unsigned) = match value.strip_prefix('-') {
+        Some(unsigned) => (true, unsigned),
+        None => (false, value.strip_prefix('+').unwrap_or(&value)),
+    };
+    let (radix, digits) = match unsigned.get(..2) {
+        Some("0x" | "0X") if options.radix_literals => (16, &unsigned[2..]),
+        Some("0o" | "0O") if options.radix_literals => (8, &unsigned[2..]),
+        Some("0b" | "0B") if options.radix_literals => (2, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    // checked here as BigInt also accepts signs and underscores
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    let i = BigInt::parse_bytes(digits.as_bytes(), radix)?;
+    Some(if negative { -i } else { i })
+}
+
+/// Fit an integer in the smallest enabled type
+fn narrow_integer(i: BigInt, options: &EngineOptions) -> Option<TypedValue> {
+    if let Ok(i) = i64::try_from(&i) {
+        return Some(TypedValue::Integer(i));
+    }
+    if options.unsigned_integers {
+        if let Ok(u) = u64::try_from(&i) {
+            return Some(TypedValue::UInteger(u));
+        }
+    }
+    options
+        .big_integers
+        .then_some(TypedValue::BigInteger(BigInteger::new(i)))
+}
+
+/// Remove the underscores placed between two digits, ie `1_000`. The value is
+/// kept as it is if the option is disabled or an underscore is misplaced.
+fn remove_digit_separators<'a>(value: &'a str, options: &EngineOptions) -> Cow<'a, str> {
+    if !options.digit_separators || !value.contains('_') {
+        return Cow::Borrowed(value);
+    }
+    let bytes = value.as_bytes();
+    let well_placed = bytes.iter().enumerate().all(|(i, &b)| {
+        b != b'_'
+            || (i > 0
+                && i + 1 < bytes.len()
+                && bytes[i - 1].is_ascii_alphanumeric()
+                && bytes[i + 1].is_ascii_alphanumeric())
+    });
+    if well_placed {
+        Cow::Owned(value.replace('_', ""))
+    } else {
+        Cow::Borrowed(value)
+    }
+}
+
+/// Read the words configured as booleans and null
+fn infer_word(value: &str, options: &EngineOptions) -> Option<TypedValue> {
+    if options.true_words.iter().any(|w| w == value) {
+        return Some(TypedValue::Bool(true));
+    }
+    if options.false_words.iter().any(|w| w == value) {
+        return Some(TypedValue::Bool(false));
+    }
+    if options.null_words.iter().any(|w| w == value) {
+        return Some(TypedValue::Null);
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaf(value: &str, options: &EngineOptions) -> TypedValue {
+        typed_leaf(value, None, options).unwrap()
+    }
+
+    fn typed_leaf(
+        value: &str,
+        value_type: Option<&str>,
+        options: &EngineOptions,
+    ) -> Result<TypedValue> {
+        match parse_value(value, value_type, options)? {
+            Node::Leaf(t) => Ok(t),
+            n => panic!("not a leaf: {:?}", n),
+        }
+    }
+
+    #[test]
+    fn test_default_words() {
+        let options = EngineOptions::new();
+        assert_eq!(leaf("true", &options), TypedValue::Bool(true));
+        assert_eq!(leaf(" false ", &options), TypedValue::Bool(false));
+        assert_eq!(leaf("null", &options), TypedValue::Null);
+        assert_eq!(leaf("yes", &options), TypedValue::String("yes".to_string()));
+        assert_eq!(leaf("nan", &options), TypedValue::String("nan".to_string()));
+    }
+
+    #[test]
+    fn test_extra_words() {
+        let options = EngineOptions::new()
+            .with_true_word("yes".to_string())
+            .with_true_word("on".to_string())
+            .with_false_word("no".to_string())
+            .with_false_word("off".to_string())
+            .with_null_word("None".to_string())
+            .with_non_finite_as_null(true);
+        assert_eq!(leaf("yes", &options), TypedValue::Bool(true));
+        assert_eq!(leaf("on", &options), TypedValue::Bool(true));
+        assert_eq!(leaf("true", &options), TypedValue::Bool(true));
+        assert_eq!(leaf("off", &options), TypedValue::Bool(false));
+        assert_eq!(leaf("None", &options), TypedValue::Null);
+        assert_eq!(leaf("nan", &options), TypedValue::Null);
+        assert_eq!(leaf("-inf", &options), TypedValue::Null);
+        assert_eq!(leaf("1.5", &options), TypedValue::Float(1.5));
+    }
+
+    #[test]
+    fn test_annotations() -> Result<()> {
+        let options = EngineOptions::new();
+        let s = |s: &str| TypedValue::String(s.to_string());
+        assert_eq!(typed_leaf("01234", Some("str"), &options)?, s("01234"));
+        assert_eq!(typed_leaf("1.10", Some("s"), &options)?, s("1.10"));
+        assert_eq!(typed_leaf("true", Some("string"), &options)?, s("true"));
+        assert_eq!(
+            typed_leaf("42", Some("i64"), &options)?,
+            TypedValue::Integer(42)
+        );
+        assert_eq!(
+            typed_leaf("1", Some("f64"), &options)?,
+            TypedValue::Float(1.0)
+        );
+        assert_eq!(
+            typed_leaf("false", Some("bool"), &options)?,
+            TypedValue::Bool(false)
+        );
+
+        assert!(matches!(
+            typed_leaf("1.5", Some("int"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            typed_leaf("a", Some("f"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            typed_leaf("inf", Some("f"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            typed_leaf("1", Some("bool"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            typed_leaf("1", Some("u8"), &options),
+            Err(Error::InvalidKey(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_extended_numbers() -> Result<()> {
+        let s = |s: &str| TypedValue::String(s.to_string());
+        let options = EngineOptions::new();
+        assert_eq!(leaf("0x1F", &options), s("0x1F"));
+        assert_eq!(leaf("1_000", &options), s("1_000"));
+        assert_eq!(
+            leaf("18446744073709551615", &options),
+            TypedValue::Float(18446744073709551615.0)
+        );
+
+        let options = EngineOptions::new()
+            .with_radix_literals(true)
+            .with_digit_separators(true);
+        assert_eq!(leaf("0x1F", &options), TypedValue::Integer(31));
+        assert_eq!(leaf("-0o17", &options), TypedValue::Integer(-15));
+        assert_eq!(leaf("0b1010", &options), TypedValue::Integer(10));
+        assert_eq!(
+            leaf("0xFFFF_FFFF", &options),
+            TypedValue::Integer(0xFFFF_FFFF)
+        );
+        assert_eq!(leaf("1_000_000", &options), TypedValue::Integer(1_000_000));
+        assert_eq!(leaf("1_000.5", &options), TypedValue::Float(1000.5));
+        assert_eq!(leaf("0x", &options), s("0x"));
+        assert_eq!(leaf("0b102", &options), s("0b102"));
+        assert_eq!(leaf("_1", &options), s("_1"));
+        assert_eq!(leaf("1__0", &options), s("1__0"));
+        assert_eq!(leaf("1_", &options), s("1_"));
+        assert_eq!(leaf("a_b", &options), s("a_b"));
+
+        let options = EngineOptions::new()
+            .with_radix_literals(true)
+            .with_unsigned_integers(true);
+        assert_eq!(
+            leaf("0xFFFFFFFFFFFFFFFF", &options),
+            TypedValue::UInteger(u64::MAX)
+        );
+        assert_eq!(
+            leaf("18446744073709551616", &options),
+            TypedValue::Float(18446744073709551616.0)
+        );
+
+        let options = EngineOptions::new().with_big_integers(true);
+        let big = "-123456789012345678901234567890";
+        assert_eq!(leaf(big, &options), TypedValue::BigInteger(big.parse()?));
+        assert_eq!(
+            typed_leaf("18446744073709551615", Some("int"), &options)?,
+            TypedValue::BigInteger(BigInteger::new(u64::MAX.into()))
+        );
+        assert_eq!(
+            typed_leaf("18446744073709551615", Some("u64"), &options)?,
+            TypedValue::UInteger(u64::MAX)
+        );
+        assert!(matches!(
+            typed_leaf("-1", Some("u64"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_and_units() -> Result<()> {
+        let m = |value: i64, unit: &str| serde_json::json!({"value": value, "unit": unit});
+        let node = |value: &str, value_type: Option<&str>, options: &EngineOptions| {
+            parse_value(value, value_type, options).map(|n| serde_json::to_value(n).unwrap())
+        };
+        for format in [TimeFormat::Canonical, TimeFormat::Epoch] {
+            let options = EngineOptions::new()
+                .with_time_format(format)
+                .with_unit_mode(UnitMode::Object);
+            // a single unit is a unit, even when it is also a duration
+            assert_eq!(node("5m", None, &options)?, m(5, "m"));
+            assert_eq!(node("2h", None, &options)?, m(2, "h"));
+            assert_eq!(node("5 m", None, &options)?, m(5, "m"));
+            // several parts are a duration
+            let d = node("1h2m", None, &options)?;
+            assert!(d == serde_json::json!("PT1H2M") || d == serde_json::json!(3720));
+            let d = node("5m", Some("duration"), &options)?;
+            assert!(d == serde_json::json!("PT5M") || d == serde_json::json!(300));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_time() -> Result<()> {
+        let s = |s: &str| TypedValue::String(s.to_string());
+        let options = EngineOptions::new();
+        let t = "2024-03-01 12:30:00+02:00";
+        assert_eq!(leaf(t, &options), s(t));
+        assert_eq!(leaf("1h2m3s", &options), s("1h2m3s"));
+        assert_eq!(typed_leaf(t, Some("time"), &options)?, s(t));
+        assert_eq!(
+            typed_leaf("1700000000", Some("epoch"), &options)?,
+            TypedValue::Integer(1700000000)
+        );
+        assert!(matches!(
+            typed_leaf("2024-03-01T25:00", Some("time"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            typed_leaf("1y", Some("duration"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+        // non-ascii digits are no timestamp
+        assert!(matches!(
+            typed_leaf("٢٠٢٤-٠٣-٠١", Some("time"), &options),
+            Err(Error::InvalidValue(_))
+        ));
+
+        let options = EngineOptions::new().with_time_format(TimeFormat::Canonical);
+        assert_eq!(leaf(t, &options), s("2024-03-01T10:30:00Z"));
+        assert_eq!(leaf("1h2m3s", &options), s("PT1H2M3S"));
+        assert_eq!(leaf("٢٠٢٤-٠٣-٠١", &options), s("٢٠٢٤-٠٣-٠١"));
+        assert_eq!(
+            leaf("1700000000", &options),
+            TypedValue::Integer(1700000000)
+        );
+        assert_eq!(
+            typed_leaf("1700000000250", Some("epoch_ms"), &options)?,
+            s("2023-11-14T22:13:20.25Z")
+        );
+
+        let options = EngineOptions::new().with_time_format(TimeFormat::Epoch);
+        assert_eq!(leaf(t, &options), TypedValue::Integer(1709289000));
+        assert_eq!(leaf("1.5s", &options), TypedValue::Float(1.5));
+        assert_eq!(
+            typed_leaf("1700000000250", Some("epoch_ms"), &options)?,
+            TypedValue::Float(1700000000.25)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_json() -> Result<()> {
+        let options = EngineOptions::new();
+        let node = parse_value(r#"{"x":1,"y":[true,null]}"#, Some("json"), &options)?;
+        let json = serde_json::to_value(&node).unwrap();
+        assert_eq!(json, serde_json::json!({"x": 1, "y": [true, null]}));
+        let node = parse_value("3", Some("json"), &options)?;
+        assert_eq!(node, Node::Leaf(TypedValue::Integer(3)));
+        let e = parse_value("{x:1}", Some("json"), &options);
+        assert!(matches!(e, Err(Error::InvalidValue(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_quoted() -> Result<()> {
+        let options = EngineOptions::new();
+        let s = |s: &str| TypedValue::String(s.to_string());
+        assert_eq!(
+            leaf(r#""  hello\n world ""#, &options),
+            s("  hello\n world ")
+        );
+        assert_eq!(leaf(r#""42""#, &options), s("42"));
+        assert_eq!(leaf(r#""true""#, &options), s("true"));
+        assert_eq!(leaf(r#""""#, &options), s(""));
+        assert_eq!(leaf(r#""a\t\"b\"\\""#, &options), s("a\t\"b\"\\"));
+        assert_eq!(leaf(r#""\x41\u00e9\0""#, &options), s("A\u{e9}\0"));
+        assert_eq!(
+            typed_leaf(r#""42""#, Some("int"), &options)?,
+            TypedValue::Integer(42)
+        );
+
+        // not entirely quoted values are kept as they are
+        assert_eq!(leaf(r#"5""#, &options), s(r#"5""#));
+        assert_eq!(leaf(r#""a" and "b""#, &options), s(r#""a" and "b""#));
+        assert_eq!(leaf(r#""abc"#, &options), s(r#""abc"#));
+
+        assert!(matches!(
+            typed_leaf(r#""\q""#, None, &options),
+            Err(Error::InvalidValue(_))
+        ));
+        assert!(matches!(
+            typed_leaf(r#""\xZ1""#, None, &options),
+            Err(Error::InvalidValue(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_heredoc_terminator() {
+        assert_eq!(heredoc_terminator("<<EOF"), Some("EOF"));
+        assert_eq!(heredoc_terminator(" <<_end1 "), Some("_end1"));
+        assert_eq!(heredoc_terminator("<<"), None);
+        assert_eq!(heredoc_terminator("<<1"), None);
+        assert_eq!(heredoc_terminator("<<E F"), None);
+        assert_eq!(heredoc_terminator("a<<EOF"), None);
+    }
+
+    #[test]
+    fn test_no_infer() -> Result<()> {
+        let options = EngineOptions::new().with_infer_types(false);
+        assert_eq!(leaf("42", &options), TypedValue::String("42".to_string()));
+        assert_eq!(
+            leaf("true", &options),
+            TypedValue::String("true".to_string())
+        );
+        assert_eq!(
+            typed_leaf("42", Some("int"), &options)?,
+            TypedValue::Integer(42)
+        );
+        Ok(())
+    }
+}
@@ -1,3 +1,5 @@
+use indexmap::IndexMap;
+use num_bigint::BigInt;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fmt::Display;
@@ -5,10 +7,66 @@ use std::str::FromStr;
 
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 #[serde(untagged)]
+#[non_exhaustive]
 pub enum TypedValue {
     String(String),
     Integer(i64),
+    /// Integer above `i64::MAX`
+    UInteger(u64),
     Float(f64),
+    Bool(bool),
+    Null,
+    /// Integer out of the 64 bits range, written as an exact json number.
+    ///
+    /// It is never deserialized: serde_json reads the json numbers out of the
+    /// 64 bits range as floats, so a big integer is read back as a
+    /// [TypedValue::Float], rounded.
+    #[serde(skip_deserializing)]
+    BigInteger(BigInteger),
+}
+
+/// Integer out of the 64 bits range, kept exact. It is written with [Display]
+/// and read with [FromStr].
+#[derive(PartialEq, Debug, Clone)]
+pub struct BigInteger(BigInt);
+
+impl BigInteger {
+    pub(crate) fn new(i: BigInt) -> Self {
+        BigInteger(i)
+    }
+}
+
+impl Display for BigInteger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for BigInteger {
+    type Err = crate::engine::Error;
+
+    /// Read decimal digits, with an optional sign
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.strip_prefix(['+', '-']).unwrap_or(s);
+        if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(crate::engine::Error::InvalidValue(format!(
+                "'{}' is not an integer",
+                s
+            )));
+        }
+        Ok(BigInteger(s.parse().unwrap()))
+    }
+}
+
+impl Serialize for BigInteger {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let raw = serde_json::value::RawValue::from_string(self.0.to_string())
+            .map_err(serde::ser::Error::custom)?;
+        raw.serialize(serializer)
+    }
 }
 
 impl Display for TypedValue {
@@ -16,7 +74,11 @@ impl Display for TypedValue {
         match self {
             TypedValue::String(s) => write!(f, "{}", s),
             TypedValue::Integer(i) => write!(f, "{}", i),
+            TypedValue::UInteger(u) => write!(f, "{}", u),
+            TypedValue::BigInteger(i) => write!(f, "{}", i),
             TypedValue::Float(fl) => write!(f, "{}", fl),
+            TypedValue::Bool(b) => write!(f, "{}", b),
+            TypedValue::Null => write!(f, "null"),
         }
     }
 }
@@ -24,69 +86,238 @@ impl Display for TypedValue {
 /// convert string to TypedValue
 impl From<String> for TypedValue {
     fn from(s: String) -> Self {
-        if let Ok(i) = i64::from_str_radix(&s, 10) {
+        match s.as_str() {
+            "true" => TypedValue::Bool(true),
+            "false" => TypedValue::Bool(false),
+            "null" => TypedValue::Null,
+            _ => TypedValue::from_number_or_string(s),
+        }
+    }
+}
+
+impl TypedValue {
+    /// Convert string to an integer or a float, keeping it as a string otherwise.
+    /// Non finite floats (`nan`, `inf`) can't be written in json and are kept as strings.
+    pub(crate) fn from_number_or_string(s: String) -> Self {
+        if let Ok(i) = s.parse::<i64>() {
             return TypedValue::Integer(i);
         }
 
         if let Ok(f) = f64::from_str(&s) {
-            return TypedValue::Float(f);
+            if f.is_finite() {
+                return TypedValue::Float(f);
+            }
         }
 
         TypedValue::String(s)
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 #[serde(untagged)]
+#[non_exhaustive]
 pub enum Node {
     Leaf(TypedValue),
     Dict(MapNodes),
     Array(Vec<Node>),
 }
 
-fn _get_leafs_from_map(m: &MapNodes) -> HashMap<String, TypedValue> {
-    let mut h = HashMap::new();
-    for (k, v) in &m.nodes {
-        match v {
-            Node::Leaf(t) => {
-                h.insert(k.clone(), t.clone());
+/// convert parsed json to Node
+impl From<serde_json::Value> for Node {
+    fn from(value: serde_json::Value) -> Self {
+        match value {
+            serde_json::Value::Null => Node::Leaf(TypedValue::Null),
+            serde_json::Value::Bool(b) => Node::Leaf(TypedValue::Bool(b)),
+            serde_json::Value::Number(n) => match (n.as_i64(), n.as_u64()) {
+                (Some(i), _) => Node::Leaf(TypedValue::Integer(i)),
+                (None, Some(u)) => Node::Leaf(TypedValue::UInteger(u)),
+                _ => Node::Leaf(TypedValue::Float(n.as_f64().unwrap_or(f64::NAN))),
+            },
+            serde_json::Value::String(s) => Node::Leaf(TypedValue::String(s)),
+            serde_json::Value::Array(a) => Node::Array(a.into_iter().map(Node::from).collect()),
+            serde_json::Value::Object(o) => {
+                let mut m = MapNodes::new();
+                for (k, v) in o {
+                    m.nodes.insert(k, Node::from(v));
+                }
+                Node::Dict(m)
             }
-            Node::Dict(m) => {
-                let s = k.clone() + ".";
-                for (k, v) in _get_leafs_from_map(m) {
-                    h.insert(s.clone() + &k, v);
+        }
+    }
+}
+
+impl Node {
+    /// Sort the keys of the dicts found in this node, recursively
+    pub fn sort_keys(&mut self) {
+        match self {
+            Node::Leaf(_) => {}
+            Node::Dict(m) => m.sort_keys(),
+            Node::Array(a) => {
+                for node in a {
+                    node.sort_keys();
+                }
+            }
+        }
+    }
+}
+
+impl Node {
+    /// Switch between an array of objects and an object of arrays, ie
+    /// `[{"x":1,"y":2},{"x":3}]` and `{"x":[1,3],"y":[2,null]}`. Returns false
+    /// if the node is neither.
+    ///
+    /// The missing fields of the objects are null in the arrays, to keep the
+    /// elements aligned, and these nulls are kept when transposing back: the
+    /// round trip gives `[{"x":1,"y":2},{"x":3,"y":null}]`, objects with
+    /// different fields not being restored.
+    pub fn transpose(&mut self) -> bool {
+        match self {
+            Node::Array(rows) if rows.iter().all(|n| matches!(n, Node::Dict(_))) => {
+                let mut columns: IndexMap<String, Vec<Node>> = IndexMap::new();
+                for (i, row) in std::mem::take(rows).into_iter().enumerate() {
+                    let Node::Dict(row) = row else { unreachable!() };
+                    for (k, v) in row.nodes {
+                        columns
+                            .entry(k)
+                            .or_insert_with(|| vec![Node::Leaf(TypedValue::Null); i])
+                            .push(v);
+                    }
+                    for column in columns.values_mut() {
+                        column.resize(i + 1, Node::Leaf(TypedValue::Null));
+                    }
                 }
-                // let mut h2 = get_leafs_from_map(&m);
-                // h.extend(h2);
+                let nodes = columns
+                    .into_iter()
+                    .map(|(k, column)| (k, Node::Array(column)))
+                    .collect();
+                *self = Node::Dict(MapNodes { nodes });
+                true
             }
-            Node::Array(_) => {
-                todo!("value is an array")
+            Node::Dict(columns) => {
+                let mut rows: Vec<MapNodes> = Vec::new();
+                for (k, column) in columns.nodes.drain(..) {
+                    let column = match column {
+                        Node::Array(a) => a,
+                        v => vec![v],
+                    };
+                    if rows.len() < column.len() {
+                        rows.resize(column.len(), MapNodes::new());
+                    }
+                    for (row, v) in rows.iter_mut().zip(column) {
+                        row.nodes.insert(k.clone(), v);
+                    }
+                }
+                *self = Node::Array(rows.into_iter().map(Node::Dict).collect());
+                true
             }
+            _ => false,
         }
     }
+}
+
+fn _get_leafs_from_map(m: &MapNodes) -> HashMap<String, TypedValue> {
+    let mut h = HashMap::new();
+    for (k, v) in &m.nodes {
+        _get_leafs_from_node(k.clone(), v, &mut h);
+    }
     h
 }
 
-#[derive(Debug, PartialEq)]
+/// The leaves of `node` found at the dotted key `key`, ie `a.b[0]`
+fn _get_leafs_from_node(key: String, node: &Node, h: &mut HashMap<String, TypedValue>) {
+    match node {
+        Node::Leaf(t) => {
+            h.insert(key, t.clone());
+        }
+        Node::Dict(m) => {
+            for (k, v) in &m.nodes {
+                _get_leafs_from_node(format!("{}.{}", key, k), v, h);
+            }
+        }
+        Node::Array(a) => {
+            for (i, v) in a.iter().enumerate() {
+                _get_leafs_from_node(format!("{}[{}]", key, i), v, h);
+            }
+        }
+    }
+}
+
+/// Keys are kept in the order they were first inserted
+#[derive(Debug, PartialEq, Default, Clone)]
 pub struct MapNodes {
-    pub nodes: HashMap<String, Node>,
+    pub(crate) nodes: IndexMap<String, Node>,
 }
 
 impl MapNodes {
     pub fn new() -> Self {
         MapNodes {
-            nodes: HashMap::new(),
+            nodes: IndexMap::new(),
         }
     }
 
+    pub fn get(&self, key: &str) -> Option<&Node> {
+        self.nodes.get(key)
+    }
+
+    /// The keys and their nodes, in order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Node)> {
+        self.nodes.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.nodes.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
 
     /// For debugging purposes
-    pub fn _json_object(&self) -> serde_json::Value {
-        serde_json::to_value(&self).unwrap()
+    pub(crate) fn _json_object(&self) -> serde_json::Value {
+        serde_json::to_value(self).unwrap()
     }
 
-    pub fn _leafs(&self) -> HashMap<String, TypedValue> {
-        _get_leafs_from_map(&self)
+    pub(crate) fn _leafs(&self) -> HashMap<String, TypedValue> {
+        _get_leafs_from_map(self)
+    }
+
+    /// The keys added or changed since `old`, recursively. The keys removed
+    /// since `old` are set to null.
+    pub fn changed_since(&self, old: &MapNodes) -> MapNodes {
+        let mut changed = MapNodes::new();
+        for (k, v) in &self.nodes {
+            match (old.nodes.get(k), v) {
+                (Some(o), v) if o == v => {}
+                (Some(Node::Dict(o)), Node::Dict(m)) => {
+                    changed
+                        .nodes
+                        .insert(k.clone(), Node::Dict(m.changed_since(o)));
+                }
+                _ => {
+                    changed.nodes.insert(k.clone(), v.clone());
+                }
+            }
+        }
+        for k in old.nodes.keys() {
+            if !self.nodes.contains_key(k) {
+                changed
+                    .nodes
+                    .insert(k.clone(), Node::Leaf(TypedValue::Null));
+            }
+        }
+        changed
+    }
+
+    /// Sort the keys, recursively
+    pub fn sort_keys(&mut self) {
+        self.nodes.sort_keys();
+        for node in self.nodes.values_mut() {
+            node.sort_keys();
+        }
     }
 }
 
@@ -104,7 +335,7 @@ impl<'de> Deserialize<'de> for MapNodes {
     where
         D: serde::Deserializer<'de>,
     {
-        let nodes = HashMap::<String, Node>::deserialize(deserializer)?;
+        let nodes = IndexMap::<String, Node>::deserialize(deserializer)?;
         Ok(MapNodes { nodes })
     }
 }
@@ -172,6 +403,75 @@ mod tests {
         assert_eq!(m, m2);
     }
 
+    #[test]
+    fn de_ser_bool_null() {
+        let s = r#"{"a":true,"b":false,"c":null}"#;
+        let m: MapNodes = serde_json::from_str(s).unwrap();
+        assert_eq!(m.nodes.get("a").unwrap(), &Leaf(TypedValue::Bool(true)));
+        assert_eq!(m.nodes.get("b").unwrap(), &Leaf(TypedValue::Bool(false)));
+        assert_eq!(m.nodes.get("c").unwrap(), &Leaf(TypedValue::Null));
+        assert_eq!(serde_json::to_string(&m).unwrap(), s);
+    }
+
+    #[test]
+    fn ser_big_integers() {
+        let mut m = MapNodes::new();
+        m.nodes
+            .insert("u".to_string(), Leaf(TypedValue::UInteger(u64::MAX)));
+        let big = "-123456789012345678901234567890".parse().unwrap();
+        m.nodes
+            .insert("b".to_string(), Leaf(TypedValue::BigInteger(big)));
+        let s = r#"{"u":18446744073709551615,"b":-123456789012345678901234567890}"#;
+        assert_eq!(serde_json::to_string(&m).unwrap(), s);
+
+        let m: MapNodes = serde_json::from_str(s).unwrap();
+        assert_eq!(m.get("u").unwrap(), &Leaf(TypedValue::UInteger(u64::MAX)));
+        // read back rounded, serde_json having no big integers
+        assert_eq!(
+            m.get("b").unwrap(),
+            &Leaf(TypedValue::Float(-1.2345678901234568e29))
+        );
+    }
+
+    #[test]
+    fn from_number_or_string() {
+        let from = |s: &str| TypedValue::from_number_or_string(s.to_string());
+        assert_eq!(from("1"), TypedValue::Integer(1));
+        assert_eq!(from("1.5"), TypedValue::Float(1.5));
+        assert_eq!(from("true"), TypedValue::String("true".to_string()));
+        assert_eq!(from("nan"), TypedValue::String("nan".to_string()));
+        assert_eq!(from("inf"), TypedValue::String("inf".to_string()));
+    }
+
+    #[test]
+    fn big_integer_from_str() {
+        let big = "-123456789012345678901234567890";
+        assert_eq!(big.parse::<BigInteger>().unwrap().to_string(), big);
+        assert!("+1".parse::<BigInteger>().is_ok());
+        for s in ["", "-", "1_0", "0x1F", "1.5", "١"] {
+            assert!(s.parse::<BigInteger>().is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn from_json_value() {
+        let s = r#"{"b":[1,2.5,"x"],"a":{"t":true,"n":null}}"#;
+        let value: serde_json::Value = serde_json::from_str(s).unwrap();
+        let node = Node::from(value);
+        let Node::Dict(m) = &node else {
+            panic!("not a dict: {:?}", node)
+        };
+        assert_eq!(
+            m.nodes.get("b").unwrap(),
+            &Node::Array(vec![
+                Leaf(TypedValue::Integer(1)),
+                Leaf(TypedValue::Float(2.5)),
+                Leaf(TypedValue::String("x".to_string())),
+            ])
+        );
+        assert_eq!(serde_json::to_string(&node).unwrap(), s);
+    }
+
     #[test]
     fn get_leaf_1() {
         let json = json!({"a":1});
@@ -204,4 +504,78 @@ mod tests {
         assert_eq!(leafs.get("a.b").unwrap(), &TypedValue::Integer(1));
         assert_eq!(leafs.get("a.c").unwrap(), &TypedValue::Integer(2));
     }
+
+    #[test]
+    fn get_leaf_array() {
+        let json = json!({"a": [1, {"b": 2}, [3]]});
+        let m: MapNodes = serde_json::from_value(json).unwrap();
+        let leafs = m._leafs();
+
+        assert_eq!(leafs.len(), 3);
+        assert_eq!(leafs.get("a[0]").unwrap(), &TypedValue::Integer(1));
+        assert_eq!(leafs.get("a[1].b").unwrap(), &TypedValue::Integer(2));
+        assert_eq!(leafs.get("a[2][0]").unwrap(), &TypedValue::Integer(3));
+    }
+
+    #[test]
+    fn de_ser_keep_order() {
+        let s = r#"{"b":1,"a":{"z":2,"y":3},"c":[{"f":4,"e":5}]}"#;
+        let m: MapNodes = serde_json::from_str(s).unwrap();
+        let keys: Vec<&String> = m.nodes.keys().collect();
+        assert_eq!(keys, vec!["b", "a", "c"]);
+        assert_eq!(serde_json::to_string(&m).unwrap(), s);
+    }
+
+    #[test]
+    fn changed_since() {
+        let old: MapNodes =
+            serde_json::from_value(json!({"a": 1, "b": {"c": 2, "d": 3}, "e": [1]})).unwrap();
+        let new: MapNodes = serde_json::from_value(
+            json!({"a": 1, "b": {"c": 2, "d": 4}, "e": [1, 2], "f": {"g": 5}}),
+        )
+        .unwrap();
+        assert_eq!(
+            new.changed_since(&old)._json_object(),
+            json!({"b": {"d": 4}, "e": [1, 2], "f": {"g": 5}})
+        );
+        assert_eq!(new.changed_since(&new)._json_object(), json!({}));
+        assert_eq!(
+            old.changed_since(&new)._json_object(),
+            json!({"b": {"d": 3}, "e": [1], "f": null})
+        );
+    }
+
+    #[test]
+    fn transpose() {
+        let mut node: Node = serde_json::from_value(json!([{"x": 1, "y": 2}, {"x": 3}])).unwrap();
+        assert!(node.transpose());
+        assert_eq!(
+            serde_json::to_value(&node).unwrap(),
+            json!({"x": [1, 3], "y": [2, null]})
+        );
+
+        let mut node: Node =
+            serde_json::from_value(json!({"x": [1, 3], "y": 2, "z": [[4], [5]]})).unwrap();
+        assert!(node.transpose());
+        assert_eq!(
+            serde_json::to_value(&node).unwrap(),
+            json!([{"x": 1, "y": 2, "z": [4]}, {"x": 3, "z": [5]}])
+        );
+
+        let mut node: Node = serde_json::from_value(json!([{"x": 1}, 2])).unwrap();
+        assert!(!node.transpose());
+        let mut node = Leaf(TypedValue::Integer(1));
+        assert!(!node.transpose());
+    }
+
+    #[test]
+    fn sort_keys() {
+        let s = r#"{"b":1,"a":{"z":2,"y":3},"c":[{"f":4,"e":5}]}"#;
+        let mut m: MapNodes = serde_json::from_str(s).unwrap();
+        m.sort_keys();
+        assert_eq!(
+            serde_json::to_string(&m).unwrap(),
+            r#"{"a":{"y":3,"z":2},"b":1,"c":[{"e":5,"f":4}]}"#
+        );
+    }
 }
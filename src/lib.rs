@@ -0,0 +1,34 @@
+//! Convert the output of a program to json.
+//!
+//! The lines holding a key and a value, ie `;a.b:1`, are merged into a json
+//! document, the other lines being ignored:
+//!
+//! ```
+//! use cout2json::{Engine, EngineOptions, Output};
+//!
+//! let mut engine = Engine::new(EngineOptions::new().with_line_prefix(";".to_string()));
+//! engine.add_line("some output").unwrap();
+//! engine.add_line(";a.b:1").unwrap();
+//! engine.add_line(";a.c:true").unwrap();
+//! engine.add_line(";!flush").unwrap();
+//! assert_eq!(
+//!     engine.take_outputs(),
+//!     vec![Output::Json(r#"{"a":{"b":1,"c":true}}"#.to_string())]
+//! );
+//! ```
+//!
+//! The engine never writes by itself: the json documents produced by the
+//! control commands, the records and the snapshots are returned by
+//! [Engine::take_outputs], the caller deciding where to write them.
+
+mod engine;
+mod jsonmodels;
+
+pub use crate::engine::command::Command;
+pub use crate::engine::engine_options::{
+    EngineOptions, HowToArrayInArray, HowToDictInArray, HowToLeafInDict, HowToRepeatedLeaf,
+    KeyOrder, MetaMode, PrefixLocation, RecordBoundary, RepeatedLeafOverride, TimeFormat, UnitMode,
+};
+pub use crate::engine::time::parse_duration;
+pub use crate::engine::{Engine, Error, Output, Result};
+pub use crate::jsonmodels::{BigInteger, MapNodes, Node, TypedValue};
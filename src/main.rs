@@ -1,10 +1,42 @@
-mod engine;
-mod jsonmodels;
+use clap::{Parser, ValueEnum};
+use cout2json::{
+    parse_duration, Engine, EngineOptions, Error, HowToArrayInArray, HowToDictInArray,
+    HowToLeafInDict, HowToRepeatedLeaf, KeyOrder, MetaMode, Output, PrefixLocation, RecordBoundary,
+    RepeatedLeafOverride, TimeFormat, UnitMode,
+};
+use regex::Regex;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
 
+/// Parse `<GLOB>=<POLICY>`, e.g. `sensors.*=last-wins`
+fn parse_repeated_leaf_override(s: &str) -> Result<RepeatedLeafOverride, String> {
+    let (pattern, how) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <GLOB>=<POLICY>, got '{}'", s))?;
+    let how = HowToRepeatedLeaf::from_str(how, true)?;
+    Ok(RepeatedLeafOverride::new(pattern, how))
+}
+
+/// Parse `<SYMBOL>=<FACTOR>`, e.g. `rpm=0.016667`
+fn parse_unit(s: &str) -> Result<(String, f64), String> {
+    let (symbol, factor) = s
+        .rsplit_once('=')
+        .ok_or_else(|| format!("expected <SYMBOL>=<FACTOR>, got '{}'", s))?;
+    let factor = factor
+        .parse::<f64>()
+        .map_err(|e| format!("invalid factor '{}': {}", factor, e))?;
+    Ok((symbol.to_string(), factor))
+}
 
-use crate::engine::engine_options::HowToDictInArray;
-use crate::engine::EngineOptions;
-use clap::Parser;
+/// Parse a duration, e.g. `500ms` or `1m30s`
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    match parse_duration(s) {
+        Some(d) if !d.is_zero() => Ok(d),
+        _ => Err(format!("expected a duration like 500ms or 2s, got '{}'", s)),
+    }
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -16,16 +48,219 @@ struct Cli {
     #[arg(long, value_enum)]
     how_to_dict_in_array: Option<HowToDictInArray>,
 
+    #[arg(long, value_enum)]
+    how_to_leaf_in_dict: Option<HowToLeafInDict>,
+
+    #[arg(long, value_enum)]
+    how_to_array_in_array: Option<HowToArrayInArray>,
+
+    /// What to do when a key already holding a value is printed again
+    #[arg(long, value_enum)]
+    how_to_repeated_leaf: Option<HowToRepeatedLeaf>,
+
+    /// Per-key policy for repeated leaves, as <GLOB>=<POLICY> (e.g. 'sensors.*=last-wins').
+    /// Can be given several times, the first matching glob wins
+    #[arg(long, value_parser = parse_repeated_leaf_override)]
+    how_to_repeated_leaf_for: Vec<RepeatedLeafOverride>,
+
     #[arg(short, long, default_value_t = false)]
     warnings_as_error: bool,
 
+    /// Order of the keys in the output
+    #[arg(long, value_enum, default_value_t = KeyOrder::Insertion)]
+    key_order: KeyOrder,
+
     /// print original cout to stderr
     #[arg(short, long)]
     tee: bool,
 
-    /// delimiter
+    /// Prefix marking the lines holding a key and a value
     #[arg(long, default_value = ";")]
-    delimiter: String,
+    prefix: String,
+
+    /// Look for the prefix anywhere in the line instead of at its start
+    #[arg(long, conflicts_with = "prefix_after")]
+    prefix_anywhere: bool,
+
+    /// Look for the prefix right after the first match of this regex
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+    prefix_after: Option<Regex>,
+
+    /// Store the text found before the prefix (or the first group of the
+    /// --prefix-after regex) under this key
+    #[arg(long, value_name = "KEY")]
+    leading_text_key: Option<String>,
+
+    /// Delimiter between the key and the value
+    #[arg(long, alias = "delimiter", default_value = ":")]
+    key_value_delimiter: String,
+
+    /// Allow several key/value pairs on a line, separated by the prefix
+    #[arg(long)]
+    multi_pair: bool,
+
+    /// Allow several key/value pairs on a line, separated by this string
+    #[arg(long, value_name = "SEPARATOR", conflicts_with = "multi_pair")]
+    pair_separator: Option<String>,
+
+    /// Read a value <<TERMINATOR as the following lines, up to a line holding
+    /// only the terminator
+    #[arg(long)]
+    multi_line: bool,
+
+    /// Separator between the keys of a dotted key
+    #[arg(long, default_value = ".")]
+    path_separator: String,
+
+    /// Keep all values as strings, unless their key has a type annotation (`;id@int:1`)
+    #[arg(long)]
+    no_infer: bool,
+
+    /// Value read as true, in addition to 'true'. Can be given several times
+    #[arg(long, value_name = "WORD")]
+    true_word: Vec<String>,
+
+    /// Value read as false, in addition to 'false'. Can be given several times
+    #[arg(long, value_name = "WORD")]
+    false_word: Vec<String>,
+
+    /// Value read as null, in addition to 'null'. Can be given several times
+    #[arg(long, value_name = "WORD")]
+    null_word: Vec<String>,
+
+    /// Read nan, inf and -inf as null instead of strings
+    #[arg(long)]
+    non_finite_as_null: bool,
+
+    /// Read the 0x1F, 0o17 and 0b1010 integer literals
+    #[arg(long)]
+    radix_literals: bool,
+
+    /// Allow underscores between the digits of a number, like 1_000_000
+    #[arg(long)]
+    digit_separators: bool,
+
+    /// Read integers above the i64 range as unsigned 64 bits integers
+    #[arg(long)]
+    unsigned_integers: bool,
+
+    /// Keep integers out of the 64 bits range exact instead of reading them as floats
+    #[arg(long)]
+    big_integers: bool,
+
+    /// How to read a number followed by a unit, like 12ms. With --time-format, a
+    /// number followed by a single unit is read as a unit (5m is 5 meters), @duration
+    /// reading it as a duration
+    #[arg(long, value_enum, default_value_t = UnitMode::Off)]
+    unit_mode: UnitMode,
+
+    /// Unit converted to SI by a factor, as <SYMBOL>=<FACTOR> (e.g. 'rpm=0.016667').
+    /// Can be given several times
+    #[arg(long, value_parser = parse_unit)]
+    unit: Vec<(String, f64)>,
+
+    /// How to write timestamps and durations. Unless 'keep', ISO-8601 timestamps
+    /// and durations like 1h2m3s are recognized. A duration like 5m is read as a
+    /// unit when --unit-mode is set
+    #[arg(long, value_enum, default_value_t = TimeFormat::Keep)]
+    time_format: TimeFormat,
+
+    /// Record the arrival time and the line number of each value
+    #[arg(long, value_enum, default_value_t = MetaMode::Off)]
+    meta: MetaMode,
+
+    /// Write a json object per line (NDJSON), a record ending when this key is
+    /// printed again. Can be given several times
+    #[arg(long, value_name = "KEY")]
+    record_on_repeat: Vec<String>,
+
+    /// Write a json object per line, a record ending on a line holding this key
+    /// (e.g. ';end:'). Can be given several times
+    #[arg(long, value_name = "KEY")]
+    record_marker: Vec<String>,
+
+    /// Write a json object per line, a record ending on a blank line
+    #[arg(long)]
+    record_on_blank: bool,
+
+    /// Write a json object per line, a record ending on a line without prefix
+    #[arg(long)]
+    record_on_unmatched: bool,
+
+    /// Write the json built so far when no line is read for this duration
+    /// (e.g. 500ms, 2s) and start over
+    #[arg(long, value_name = "DURATION", value_parser = parse_interval)]
+    flush_after: Option<Duration>,
+
+    /// Write the json built so far every N lines, without starting over
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    snapshot_every_lines: Option<u64>,
+
+    /// Write the json built so far at this interval (e.g. 10s), without starting over
+    #[arg(long, value_name = "DURATION", value_parser = parse_interval)]
+    snapshot_every: Option<Duration>,
+
+    /// A snapshot only holds the keys added or changed since the previous one,
+    /// a removed key being null
+    #[arg(long)]
+    snapshot_changes_only: bool,
+
+    /// Key holding an array of objects, a new object starting when one of its
+    /// fields is printed again (e.g. 'it' for ';it.x:1'). Can be given several times
+    #[arg(long, value_name = "KEY")]
+    group: Vec<String>,
+
+    /// Write this key as an array of objects if it holds an object of arrays, and
+    /// the other way around. Can be given several times
+    #[arg(long, value_name = "KEY")]
+    transpose: Vec<String>,
+}
+
+fn report_error(e: Error, warnings_as_error: bool) {
+    if !warnings_as_error {
+        eprintln!("Warning: {}", e);
+    } else {
+        panic!("Error: {}", e);
+    }
+}
+
+/// Write the outputs of the engine, returning false if it asks to stop
+fn write_outputs(engine: &mut Engine) -> bool {
+    let mut go_on = true;
+    for output in engine.take_outputs() {
+        match output {
+            Output::Json(json) => println!("{}", json),
+            Output::Stop => go_on = false,
+            // outputs added by later versions of the engine
+            _ => {}
+        }
+    }
+    go_on
+}
+
+/// Read the lines of the input in a thread, the channel being closed at the
+/// end. Lines which are not valid UTF-8 are read lossily instead of ending the
+/// input.
+fn spawn_reader<R: BufRead + Send + 'static>(mut input: R) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || loop {
+        let mut buffer = Vec::new();
+        match input.read_until(b'\n', &mut buffer) {
+            Ok(0) => break,
+            Ok(_) => {
+                let line = String::from_utf8_lossy(&buffer).into_owned();
+                if sender.send(line).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => {
+                eprintln!("Error: cannot read the input: {}", e);
+                break;
+            }
+        }
+    });
+    receiver
 }
 
 fn main() {
@@ -38,44 +273,179 @@ fn main() {
 
     let engine_options = EngineOptions::new();
     let engine_options = engine_options.with_verbosity(cli.verbose);
-    let mut engine_options = engine_options.with_key_delimiter(cli.delimiter);
-
+    let engine_options = engine_options.with_line_prefix(cli.prefix.clone());
+    let engine_options = engine_options.with_key_to_value_delimiter(cli.key_value_delimiter);
+    let mut engine_options = engine_options.with_path_separator(cli.path_separator);
 
     if let Some(how_to_dict_in_array) = cli.how_to_dict_in_array {
         engine_options = engine_options.with_how_to_dict_in_array(how_to_dict_in_array)
     }
+    if let Some(how_to_leaf_in_dict) = cli.how_to_leaf_in_dict {
+        engine_options = engine_options.with_how_to_leaf_in_dict(how_to_leaf_in_dict)
+    }
+    if let Some(how_to_array_in_array) = cli.how_to_array_in_array {
+        engine_options = engine_options.with_how_to_array_in_array(how_to_array_in_array)
+    }
+    if let Some(how_to_repeated_leaf) = cli.how_to_repeated_leaf {
+        engine_options = engine_options.with_how_to_repeated_leaf(how_to_repeated_leaf)
+    }
+    engine_options = engine_options.with_key_order(cli.key_order);
+    if cli.prefix_anywhere {
+        engine_options = engine_options.with_prefix_location(PrefixLocation::Anywhere)
+    }
+    if let Some(regex) = cli.prefix_after {
+        engine_options = engine_options.with_prefix_location(PrefixLocation::AfterRegex(regex))
+    }
+    if cli.multi_pair {
+        engine_options = engine_options.with_pair_separator(cli.prefix)
+    }
+    if let Some(separator) = cli.pair_separator {
+        engine_options = engine_options.with_pair_separator(separator)
+    }
+    engine_options = engine_options.with_multi_line_values(cli.multi_line);
+    engine_options = engine_options.with_infer_types(!cli.no_infer);
+    for w in cli.true_word {
+        engine_options = engine_options.with_true_word(w)
+    }
+    for w in cli.false_word {
+        engine_options = engine_options.with_false_word(w)
+    }
+    for w in cli.null_word {
+        engine_options = engine_options.with_null_word(w)
+    }
+    engine_options = engine_options.with_non_finite_as_null(cli.non_finite_as_null);
+    engine_options = engine_options
+        .with_radix_literals(cli.radix_literals)
+        .with_digit_separators(cli.digit_separators)
+        .with_unsigned_integers(cli.unsigned_integers)
+        .with_big_integers(cli.big_integers);
+    engine_options = engine_options.with_unit_mode(cli.unit_mode);
+    engine_options = engine_options.with_time_format(cli.time_format);
+    engine_options = engine_options.with_meta_mode(cli.meta);
+    engine_options = engine_options.with_snapshot_changes_only(cli.snapshot_changes_only);
+    for key in cli.record_on_repeat {
+        engine_options = engine_options.with_record_boundary(RecordBoundary::RepeatedKey(key))
+    }
+    for key in cli.record_marker {
+        engine_options = engine_options.with_record_boundary(RecordBoundary::MarkerKey(key))
+    }
+    if cli.record_on_blank {
+        engine_options = engine_options.with_record_boundary(RecordBoundary::BlankLine)
+    }
+    if cli.record_on_unmatched {
+        engine_options = engine_options.with_record_boundary(RecordBoundary::UnmatchedLine)
+    }
+    for key in cli.group {
+        engine_options = engine_options.with_group_key(key)
+    }
+    for key in cli.transpose {
+        engine_options = engine_options.with_transpose_key(key)
+    }
+    for (symbol, factor) in cli.unit {
+        engine_options = engine_options.with_unit(symbol, factor)
+    }
+    if let Some(key) = cli.leading_text_key {
+        engine_options = engine_options.with_leading_text_key(key)
+    }
+    for o in cli.how_to_repeated_leaf_for {
+        engine_options = engine_options.with_repeated_leaf_override(o)
+    }
 
     // println!("engine_options = {:?}", engine_options);
 
-    let mut engine = engine::Engine::new(engine_options);
+    let mut engine = Engine::new(engine_options);
 
+    let lines = spawn_reader(BufReader::new(std::io::stdin()));
+    let mut line_count = 0;
+    let mut idle_deadline = cli.flush_after.map(|idle| Instant::now() + idle);
+    let mut snapshot_deadline = cli.snapshot_every.map(|every| Instant::now() + every);
     loop {
-        let mut buffer = String::new();
-        let size = std::io::stdin().read_line(&mut buffer);
-        match size {
-            Ok(size) => {
-                // println!("size: '{}'", size);
-                // println!("buffer: '{}'", buffer);
+        let now = Instant::now();
+        if idle_deadline.is_some_and(|deadline| deadline <= now) {
+            engine.end_record();
+            idle_deadline = None;
+        }
+        if snapshot_deadline.is_some_and(|deadline| deadline <= now) {
+            engine.snapshot();
+            snapshot_deadline = cli.snapshot_every.map(|every| now + every);
+        }
+        write_outputs(&mut engine);
+
+        let line = match idle_deadline.into_iter().chain(snapshot_deadline).min() {
+            Some(deadline) => match lines.recv_timeout(deadline.saturating_duration_since(now)) {
+                Ok(line) => Some(line),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => None,
+            },
+            None => lines.recv().ok(),
+        };
+
+        match line {
+            Some(buffer) => {
                 if cli.tee {
                     eprint!("{}", buffer);
                 }
-                match engine.add_line(&buffer) {
-                    Ok(_) => {}
-                    Err(e) => {
-                        if cli.warnings_as_error == false {
-                            eprintln!("Warning: {}", e);
-                        } else {
-                            panic!("Error: {}", e);
-                        }
-                    }
+                let result = engine.add_line(&buffer);
+                // the outputs produced before an error are written anyway
+                let go_on = write_outputs(&mut engine);
+                if let Err(e) = result {
+                    report_error(e, cli.warnings_as_error);
                 }
-
-                if size == 0 {
-                    println!("{}", engine.get_json());
+                if !go_on {
+                    // the values are written already, only the checks are left
+                    if let Err(e) = engine.finish() {
+                        report_error(e, cli.warnings_as_error);
+                    }
                     break;
                 }
+                idle_deadline = cli.flush_after.map(|idle| Instant::now() + idle);
+                line_count += 1;
+                if cli
+                    .snapshot_every_lines
+                    .is_some_and(|n| line_count % n == 0)
+                {
+                    engine.snapshot();
+                    write_outputs(&mut engine);
+                }
+            }
+            None => {
+                if let Err(e) = engine.finish() {
+                    report_error(e, cli.warnings_as_error);
+                }
+                if engine.is_record_mode() || cli.flush_after.is_some() {
+                    engine.end_record();
+                } else {
+                    engine.flush();
+                }
+                write_outputs(&mut engine);
+                break;
             }
-            Err(_) => {}
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("500ms"), Ok(Duration::from_millis(500)));
+        assert_eq!(parse_interval("1m30s"), Ok(Duration::from_secs(90)));
+        assert_eq!(parse_interval("1.5s"), Ok(Duration::from_millis(1500)));
+        assert_eq!(parse_interval("2min"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_interval("1d"), Ok(Duration::from_secs(86400)));
+        assert_eq!(parse_interval("250us"), Ok(Duration::from_micros(250)));
+        for s in ["", "0s", "12", "1x", "1 s", "s"] {
+            assert!(parse_interval(s).is_err(), "{}", s);
+        }
+    }
+
+    #[test]
+    fn test_reader_invalid_utf8() {
+        let input = Cursor::new(b";a:1\n\xff\xfe\n;b:2".to_vec());
+        let lines: Vec<String> = spawn_reader(input).iter().collect();
+        assert_eq!(lines, vec![";a:1\n", "\u{fffd}\u{fffd}\n", ";b:2"]);
+    }
+}
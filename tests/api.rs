@@ -0,0 +1,133 @@
+use cout2json::{Engine, EngineOptions, Error, MetaMode, Node, Output, RecordBoundary, TypedValue};
+
+fn json(s: &str) -> Output {
+    Output::Json(s.to_string())
+}
+
+#[test]
+fn add_line() -> anyhow::Result<()> {
+    let mut engine = Engine::new(EngineOptions::new());
+    engine.add_line("some output\n")?;
+    engine.add_line(";a.b:1\n")?;
+    engine.add_line(";a.c:x\n")?;
+    engine.add_line(";a.c:2.5\n")?;
+    engine.finish()?;
+    assert_eq!(engine.get_json(), r#"{"a":{"b":1,"c":["x",2.5]}}"#);
+
+    let Some(Node::Dict(a)) = engine.values().get("a") else {
+        panic!("a is not a dict: {:?}", engine.values());
+    };
+    assert_eq!(a.keys().collect::<Vec<_>>(), vec!["b", "c"]);
+    assert_eq!(a.get("b"), Some(&Node::Leaf(TypedValue::Integer(1))));
+    assert!(engine.take_outputs().is_empty());
+    Ok(())
+}
+
+#[test]
+fn errors() -> anyhow::Result<()> {
+    let mut engine = Engine::new(EngineOptions::new());
+    engine.add_line(";a:1")?;
+    let e = engine.add_line(";!delete:b");
+    assert!(matches!(e, Err(Error::MissingKey(_))));
+    let e = engine.add_line(";n@int:x");
+    assert!(matches!(e, Err(Error::InvalidValue(_))));
+    // an error leaves the engine usable
+    engine.add_line(";c:1")?;
+    assert_eq!(engine.get_json(), r#"{"a":1,"c":1}"#);
+    Ok(())
+}
+
+#[test]
+fn finish() -> anyhow::Result<()> {
+    let mut engine = Engine::new(EngineOptions::new().with_multi_line_values(true));
+    engine.add_line(";text:<<EOF")?;
+    engine.add_line("line")?;
+    assert!(matches!(engine.finish(), Err(Error::UnterminatedBlock(_))));
+
+    let mut engine = Engine::new(EngineOptions::new());
+    engine.add_line(";!begin:a")?;
+    engine.add_line(";x:1")?;
+    assert!(matches!(engine.finish(), Err(Error::UnbalancedScope(_))));
+    assert_eq!(engine.get_json(), r#"{"a":{"x":1}}"#);
+    Ok(())
+}
+
+#[test]
+fn outputs() -> anyhow::Result<()> {
+    let mut engine = Engine::new(EngineOptions::new());
+    engine.add_line(";a:1")?;
+    engine.add_line(";!snapshot")?;
+    engine.add_line(";b:1")?;
+    engine.add_line(";!flush")?;
+    engine.add_line(";c.d:1")?;
+    engine.add_line(";!emit:c")?;
+    engine.add_line(";e:1")?;
+    engine.add_line(";!exit")?;
+    assert_eq!(
+        engine.take_outputs(),
+        vec![
+            json(r#"{"a":1}"#),
+            json(r#"{"a":1,"b":1}"#),
+            json(r#"{"d":1}"#),
+            json(r#"{"e":1}"#),
+            Output::Stop
+        ]
+    );
+    assert!(engine.take_outputs().is_empty());
+    assert_eq!(engine.get_json(), "{}");
+    Ok(())
+}
+
+#[test]
+fn records() -> anyhow::Result<()> {
+    let options = EngineOptions::new()
+        .with_record_boundary(RecordBoundary::RepeatedKey("id".to_string()))
+        .with_record_boundary(RecordBoundary::BlankLine);
+    let mut engine = Engine::new(options);
+    assert!(engine.is_record_mode());
+    engine.add_line(";id:1")?;
+    engine.add_line(";x:1")?;
+    engine.add_line(";id:2")?;
+    engine.add_line("")?;
+    engine.add_line("")?;
+    engine.add_line(";id:3")?;
+    engine.end_record();
+    engine.end_record();
+    assert_eq!(
+        engine.take_outputs(),
+        vec![
+            json(r#"{"id":1,"x":1}"#),
+            json(r#"{"id":2}"#),
+            json(r#"{"id":3}"#)
+        ]
+    );
+    Ok(())
+}
+
+#[test]
+fn snapshots() -> anyhow::Result<()> {
+    let options = EngineOptions::new().with_snapshot_changes_only(true);
+    let mut engine = Engine::new(options);
+    engine.add_line(";a:1")?;
+    engine.snapshot();
+    engine.add_line(";b:1")?;
+    engine.snapshot();
+    engine.snapshot();
+    assert_eq!(
+        engine.take_outputs(),
+        vec![json(r#"{"a":1}"#), json(r#"{"b":1}"#), json("{}")]
+    );
+    assert_eq!(engine.get_json(), r#"{"a":1,"b":1}"#);
+    Ok(())
+}
+
+#[test]
+fn meta() -> anyhow::Result<()> {
+    let options = EngineOptions::new().with_meta_mode(MetaMode::Inline);
+    let mut engine = Engine::new(options);
+    engine.add_line(";a:1")?;
+    let value: serde_json::Value = serde_json::from_str(&engine.get_json())?;
+    assert_eq!(value["a"]["v"], 1);
+    assert_eq!(value["a"]["line"], 1);
+    Ok(())
+}